@@ -0,0 +1,82 @@
+use rand::Rng;
+use rusqlite::{params, OptionalExtension};
+use sha2::{Digest, Sha256};
+use crate::store::{Store, now};
+
+/// An API key of user, for OpenSubsonic `apiKey` authentication.
+///
+/// Only the hash of key is stored, so the key itself is shown to user only once when created.
+pub struct ApiKeyRecord {
+    pub id: i64,
+    pub name: String,
+    pub created: i64,
+    pub last_used: Option<i64>,
+}
+
+fn hash(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+impl Store {
+    /// Create a new random key for `username`, returning its id and the key.
+    pub fn create_api_key(&self, username: &str, name: &str) -> rusqlite::Result<(i64, String)> {
+        let key = hex::encode(rand::thread_rng().gen::<[u8; 32]>());
+        let conn = self.conn();
+        conn.execute("INSERT INTO api_key (username, name, hash, created) VALUES (?1, ?2, ?3, ?4)",
+                     params![username, name, hash(&key), now()])?;
+        Ok((conn.last_insert_rowid(), key))
+    }
+
+    pub fn api_keys(&self, username: &str) -> rusqlite::Result<Vec<ApiKeyRecord>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT id, name, created, last_used FROM api_key WHERE username = ?1 ORDER BY id")?;
+        let keys = stmt.query_map(params![username], |row| Ok(ApiKeyRecord {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            created: row.get(2)?,
+            last_used: row.get(3)?,
+        }))?.collect();
+        keys
+    }
+
+    /// Revoke key `id` of `username`, returns whether the key existed.
+    pub fn delete_api_key(&self, username: &str, id: i64) -> rusqlite::Result<bool> {
+        let deleted = self.conn().execute("DELETE FROM api_key WHERE id = ?1 AND username = ?2", params![id, username])?;
+        Ok(deleted > 0)
+    }
+
+    /// Owner of `key`, and mark the key as used.
+    pub fn api_key_user(&self, key: &str) -> rusqlite::Result<Option<String>> {
+        let conn = self.conn();
+        let hash = hash(key);
+        let username = conn.query_row("SELECT username FROM api_key WHERE hash = ?1", params![hash], |row| row.get(0))
+            .optional()?;
+        if username.is_some() {
+            conn.execute("UPDATE api_key SET last_used = ?2 WHERE hash = ?1", params![hash, now()])?;
+        }
+        Ok(username)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::Store;
+
+    #[test]
+    fn test_api_key() {
+        let store = Store::memory().unwrap();
+        let (id, key) = store.create_api_key("alice", "phone").unwrap();
+        assert_eq!(store.api_key_user(&key).unwrap().as_deref(), Some("alice"));
+        assert_eq!(store.api_key_user("wrong").unwrap(), None);
+
+        let keys = store.api_keys("alice").unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].name, "phone");
+        assert!(keys[0].last_used.is_some());
+
+        // keys can only be revoked by owner
+        assert!(!store.delete_api_key("bob", id).unwrap());
+        assert!(store.delete_api_key("alice", id).unwrap());
+        assert_eq!(store.api_key_user(&key).unwrap(), None);
+    }
+}
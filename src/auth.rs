@@ -4,27 +4,108 @@ use std::task::{Context, Poll};
 use actix_web::{dev::ServiceRequest, dev::ServiceResponse, Error};
 use actix_utils::future::{ok, Ready};
 use std::future::Future;
-use actix_web::web::Query;
 use serde::Deserialize;
 use actix_web::dev::{Transform, Service};
+use actix_web::HttpMessage;
+use std::sync::Arc;
+use crate::config::UserConfig;
+use crate::error;
+use crate::forwarded::Proxies;
+use crate::lockout::Lockout;
+use crate::params::Params;
+use crate::response::VERSION;
+use crate::store::{self, Store};
+use crate::user::Users;
 
 #[derive(Debug, Deserialize)]
 struct Auth {
     #[serde(rename = "u")]
-    username: String,
+    username: Option<String>,
     #[serde(rename = "p")]
     password: Option<String>,
-    #[serde(rename = "t", default)]
-    token: String,
-    #[serde(rename = "s", default)]
-    salt: String,
+    #[serde(rename = "t")]
+    token: Option<String>,
+    #[serde(rename = "s")]
+    salt: Option<String>,
     #[serde(rename = "c", default)]
     client: String,
-    #[serde(rename = "v", default)]
-    version: String,
+    #[serde(rename = "v")]
+    version: Option<String>,
+    #[serde(rename = "apiKey")]
+    api_key: Option<String>,
 }
 
-pub struct SonicAuth;
+/// Check that client speaks a compatible protocol version.
+///
+/// Only major versions are compared, as clients commonly request a newer minor version than the server supports
+/// while using none of the newer features.
+fn check_version(version: &str) -> Result<(), error::Error> {
+    let major = |version: &str| version.split('.').next().and_then(|major| major.parse::<u32>().ok());
+    match (major(version), major(VERSION)) {
+        (Some(client), Some(server)) if client < server => Err(error::Error::ClientTooOld),
+        (Some(client), Some(server)) if client > server => Err(error::Error::ServerTooOld),
+        (Some(_), Some(_)) => Ok(()),
+        _ => Err(error::Error::InvalidParameter(format!("Invalid version: {}", version))),
+    }
+}
+
+/// Find user by credentials in `auth`.
+fn authenticate<'a>(auth: &Auth, users: &'a Users, store: &Store, token_auth: bool) -> Result<&'a UserConfig, error::Error> {
+    // clients not sending version are not rejected, as many of them work fine
+    if let Some(version) = &auth.version {
+        check_version(version)?;
+    }
+    if let Some(key) = &auth.api_key {
+        // the key tells the user, so `u` is not allowed either
+        if auth.username.is_some() || auth.password.is_some() || auth.token.is_some() {
+            return Err(error::Error::ConflictingAuthMechanisms);
+        }
+        let username = store.api_key_user(key)?.ok_or(error::Error::InvalidApiKey)?;
+        // user may have been removed from config after the key was created
+        return users.get(&username).ok_or(error::Error::InvalidApiKey);
+    }
+    let username = auth.username.as_deref().ok_or(error::Error::MissingParameter("u"))?;
+    let user = users.get(username);
+    let valid = match (&auth.password, &auth.token) {
+        (Some(password), _) => user.filter(|user| match password.strip_prefix("enc:") {
+            Some(hex) => hex::decode(hex).map(|p| p == user.password.as_bytes()).unwrap_or(false),
+            None => password == &user.password,
+        }).is_some(),
+        (None, Some(_)) if !token_auth => return Err(error::Error::TokenAuthNotSupported),
+        // t = md5(password+s)
+        (None, Some(token)) => match &auth.salt {
+            Some(salt) if !salt.is_empty() => user.filter(|user| {
+                token == &format!("{:x}", md5::compute(user.password.clone() + salt))
+            }).is_some(),
+            // token without salt is just md5 of password, which is not accepted
+            _ => return Err(error::Error::AuthMechanismNotSupported),
+        },
+        (None, None) => return Err(error::Error::MissingParameter("p")),
+    };
+    match user {
+        Some(user) if valid => Ok(user),
+        _ => Err(error::Error::WrongCredentials),
+    }
+}
+
+/// Client name from `c` parameter, inserted into request extensions after authorized.
+#[derive(Clone)]
+pub struct Client(pub String);
+
+pub struct SonicAuth {
+    users: Arc<Users>,
+    store: Arc<Store>,
+    lockout: Arc<Lockout>,
+    proxies: Proxies,
+    /// Whether token authentication is allowed, see [`crate::config::ServerConfig::token_auth`]
+    token_auth: bool,
+}
+
+impl SonicAuth {
+    pub fn new(users: Arc<Users>, store: Arc<Store>, lockout: Arc<Lockout>, proxies: Proxies, token_auth: bool) -> Self {
+        Self { users, store, lockout, proxies, token_auth }
+    }
+}
 
 impl<S> Transform<S, ServiceRequest> for SonicAuth
     where
@@ -38,12 +119,24 @@ impl<S> Transform<S, ServiceRequest> for SonicAuth
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        ok(SonicAuthMiddleware { service })
+        ok(SonicAuthMiddleware {
+            service,
+            users: self.users.clone(),
+            store: self.store.clone(),
+            lockout: self.lockout.clone(),
+            proxies: self.proxies.clone(),
+            token_auth: self.token_auth,
+        })
     }
 }
 
 pub struct SonicAuthMiddleware<S> {
     service: S,
+    users: Arc<Users>,
+    store: Arc<Store>,
+    lockout: Arc<Lockout>,
+    proxies: Proxies,
+    token_auth: bool,
 }
 
 impl<S> Service<ServiceRequest> for SonicAuthMiddleware<S>
@@ -60,36 +153,97 @@ impl<S> Service<ServiceRequest> for SonicAuthMiddleware<S>
     }
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        let query = Query::<Auth>::from_query(req.query_string());
-        match query {
-            Ok(query) => {
-                let query = query.into_inner();
-                // t = md5(password+s)
-                if query.username == std::env::var("ANNI_USER").unwrap()
-                    && match query.password {
-                    None => { query.token == format!("{:x}", md5::compute(std::env::var("ANNI_PASSWD").unwrap() + &query.salt)) }
-                    Some(password) => {
-                        let password = if password.starts_with("enc:") { &password[4..] } else { &password };
-                        password == std::env::var("ANNI_PASSWD_HEX").unwrap()
+        // OpenSubsonic requires extensions to be listed without authentication
+        if req.path().ends_with("/getOpenSubsonicExtensions.view") {
+            return Box::pin(self.service.call(req));
+        }
+        let ip = self.proxies.resolve(req.head(), req.app_config().secure()).ip;
+        let now = store::now();
+        let params = req.extensions().get::<Params>().cloned()
+            .unwrap_or_else(|| Params::from_query(req.query_string()));
+        let user = params.parse::<Auth>()
+            .and_then(|query| {
+                let username = query.username.as_deref();
+                // locked out clients are rejected without checking credentials
+                if let Some(wait) = self.lockout.locked(ip, username, now) {
+                    return Err(error::Error::LockedOut(wait));
+                }
+                match authenticate(&query, &self.users, &self.store, self.token_auth) {
+                    Ok(user) => {
+                        self.lockout.succeeded(ip, &user.username);
+                        Ok((user.clone(), Client(query.client)))
+                    }
+                    Err(e) => {
+                        if matches!(e, error::Error::WrongCredentials | error::Error::InvalidApiKey) {
+                            self.lockout.failed(ip, username, now);
+                        }
+                        Err(e)
                     }
-                } {
-                    let fut = self.service.call(req);
-                    Box::pin(async {
-                        let res = fut.await?;
-                        Ok(res)
-                    })
-                } else {
-                    // wrong password
-                    Box::pin(async {
-                        let res = req.error_response(std::io::Error::new(std::io::ErrorKind::InvalidInput, ""));
-                        Ok(res)
-                    })
                 }
+            });
+        match user {
+            Ok((user, client)) => {
+                req.extensions_mut().insert(user);
+                req.extensions_mut().insert(client);
+                let fut = self.service.call(req);
+                Box::pin(async {
+                    let res = fut.await?;
+                    Ok(res)
+                })
             }
-            Err(_) => Box::pin(async {
-                let res = req.error_response(std::io::Error::new(std::io::ErrorKind::InvalidInput, ""));
+            // rendered with status code by error handler
+            Err(e) => Box::pin(async {
+                let res = req.error_response(e);
                 Ok(res)
-            })
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::{authenticate, Auth};
+    use crate::params::Params;
+    use crate::store::Store;
+    use crate::test_util;
+    use crate::user::Users;
+
+    fn users() -> Users {
+        Users::new(&test_util::config(""))
+    }
+
+    fn code(query: &str, store: &Store, token_auth: bool) -> u32 {
+        let auth = Params::from_query(query).parse::<Auth>().unwrap();
+        match authenticate(&auth, &users(), store, token_auth) {
+            Ok(_) => 0,
+            Err(e) => e.code(),
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_authenticate() {
+        let store = Store::memory().unwrap();
+        assert_eq!(code("u=alice&p=sesame&v=1.16.1", &store, true), 0);
+        assert_eq!(code("u=alice&p=enc:736573616d65", &store, true), 0);
+        // md5("sesame" + "salt")
+        let token = format!("{:x}", md5::compute("sesamesalt"));
+        assert_eq!(code(&format!("u=alice&t={}&s=salt", token), &store, true), 0);
+
+        assert_eq!(code("p=sesame", &store, true), 10);
+        assert_eq!(code("u=alice", &store, true), 10);
+        assert_eq!(code("u=alice&p=wrong", &store, true), 40);
+        assert_eq!(code("u=bob&p=sesame", &store, true), 40);
+        assert_eq!(code(&format!("u=alice&t={}&s=salt", token), &store, false), 41);
+        assert_eq!(code(&format!("u=alice&t={}", token), &store, true), 42);
+        assert_eq!(code("u=alice&p=sesame&v=0.9", &store, true), 20);
+        assert_eq!(code("u=alice&p=sesame&v=2.0.0", &store, true), 30);
+        assert_eq!(code("u=alice&p=sesame&v=latest", &store, true), 10);
+
+        let (_, key) = store.create_api_key("alice", "phone").unwrap();
+        assert_eq!(code(&format!("apiKey={}", key), &store, true), 0);
+        assert_eq!(code(&format!("apiKey={}&u=alice", key), &store, true), 43);
+        assert_eq!(code("apiKey=wrong", &store, true), 44);
+        let (_, key) = store.create_api_key("bob", "removed").unwrap();
+        assert_eq!(code(&format!("apiKey={}", key), &store, true), 44);
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use crate::store::now;
+
+struct Cached {
+    albums: Arc<HashSet<String>>,
+    fetched: Instant,
+}
+
+#[derive(Default)]
+struct Metrics {
+    refreshes: AtomicU64,
+    failures: AtomicU64,
+    /// Unix timestamp of the last successful refresh
+    last_success: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+/// Catalogs of albums available in annil, cached for `ttl`.
+///
+/// If annil fails to respond, the last fetched album set is served until the next successful refresh.
+pub struct Availability {
+    ttl: Duration,
+    cached: RwLock<Option<Cached>>,
+    /// Held while refreshing, so that only one request fetches from annil at a time.
+    /// Others serve the cached copy meanwhile, or wait for the refresh if there is none.
+    refreshing: tokio::sync::Mutex<()>,
+    metrics: Metrics,
+}
+
+impl Availability {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            cached: RwLock::new(None),
+            refreshing: tokio::sync::Mutex::new(()),
+            metrics: Metrics::default(),
+        }
+    }
+
+    /// Get available albums, refreshing them with `fetch` if expired.
+    pub async fn get<F>(&self, fetch: F) -> anyhow::Result<Arc<HashSet<String>>>
+        where F: Future<Output=anyhow::Result<Vec<String>>>
+    {
+        let stale = match self.cached.read().unwrap().as_ref() {
+            Some(cached) if cached.fetched.elapsed() < self.ttl => return Ok(cached.albums.clone()),
+            Some(cached) => Some(cached.albums.clone()),
+            None => None,
+        };
+        let _refreshing = match stale {
+            Some(stale) => match self.refreshing.try_lock() {
+                Ok(guard) => guard,
+                Err(_) => return Ok(stale),
+            },
+            None => self.refreshing.lock().await,
+        };
+        // refreshed by another request while waiting
+        if let Some(cached) = self.cached.read().unwrap().as_ref() {
+            if cached.fetched.elapsed() < self.ttl {
+                return Ok(cached.albums.clone());
+            }
+        }
+        self.fetch(fetch).await
+    }
+
+    /// Refresh available albums with `fetch` now.
+    ///
+    /// The last fetched album set is returned if `fetch` fails, and error is returned only if there is none.
+    pub async fn refresh<F>(&self, fetch: F) -> anyhow::Result<Arc<HashSet<String>>>
+        where F: Future<Output=anyhow::Result<Vec<String>>>
+    {
+        let _refreshing = self.refreshing.lock().await;
+        self.fetch(fetch).await
+    }
+
+    /// Fetch available albums, while holding `refreshing`.
+    async fn fetch<F>(&self, fetch: F) -> anyhow::Result<Arc<HashSet<String>>>
+        where F: Future<Output=anyhow::Result<Vec<String>>>
+    {
+        let result = fetch.await;
+        self.metrics.refreshes.fetch_add(1, Ordering::Relaxed);
+        match result {
+            Ok(albums) => {
+                let albums = Arc::new(albums.into_iter().collect::<HashSet<_>>());
+                *self.cached.write().unwrap() = Some(Cached { albums: albums.clone(), fetched: Instant::now() });
+                self.metrics.last_success.store(now() as u64, Ordering::Relaxed);
+                Ok(albums)
+            }
+            Err(e) => {
+                self.metrics.failures.fetch_add(1, Ordering::Relaxed);
+                *self.metrics.last_error.lock().unwrap() = Some(e.to_string());
+                match self.cached.read().unwrap().as_ref() {
+                    Some(cached) => {
+                        log::warn!("Failed to refresh album list from annil, serving cached copy: {}", e);
+                        Ok(cached.albums.clone())
+                    }
+                    None => Err(e),
+                }
+            }
+        }
+    }
+
+    /// The last fetched album set, without refreshing.
+    pub fn cached(&self) -> Option<Arc<HashSet<String>>> {
+        self.cached.read().unwrap().as_ref().map(|cached| cached.albums.clone())
+    }
+
+    pub fn status(&self) -> Status {
+        let last_success = self.metrics.last_success.load(Ordering::Relaxed);
+        Status {
+            albums: self.cached().map(|albums| albums.len()).unwrap_or(0),
+            refreshes: self.metrics.refreshes.load(Ordering::Relaxed),
+            failures: self.metrics.failures.load(Ordering::Relaxed),
+            last_success: if last_success > 0 { Some(last_success as i64) } else { None },
+            last_error: self.metrics.last_error.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// Refresh statistics of album list.
+#[derive(Serialize)]
+pub struct Status {
+    pub albums: usize,
+    pub refreshes: u64,
+    pub failures: u64,
+    /// Unix timestamp of the last successful refresh
+    pub last_success: Option<i64>,
+    pub last_error: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use crate::availability::Availability;
+
+    fn albums(catalogs: &[&str]) -> anyhow::Result<Vec<String>> {
+        Ok(catalogs.iter().map(|c| c.to_string()).collect())
+    }
+
+    #[test]
+    fn test_availability() {
+        actix_web::rt::System::new().block_on(async {
+            let cache = Availability::new(Duration::from_secs(60));
+            // nothing to serve before the first successful fetch
+            assert!(cache.get(async { anyhow::bail!("annil is down") }).await.is_err());
+
+            let result = cache.get(async { albums(&["TEST-001", "TEST-002"]) }).await.unwrap();
+            assert!(result.contains("TEST-002"));
+            // not expired, fetch is not called
+            let result = cache.get(async { albums(&[]) }).await.unwrap();
+            assert_eq!(result.len(), 2);
+
+            // last good copy is served on failure
+            let result = cache.refresh(async { anyhow::bail!("annil is down") }).await.unwrap();
+            assert_eq!(result.len(), 2);
+            assert_eq!(cache.status().failures, 2);
+            assert_eq!(cache.status().last_error.as_deref(), Some("annil is down"));
+
+            let result = cache.refresh(async { albums(&["TEST-003"]) }).await.unwrap();
+            assert!(result.contains("TEST-003") && !result.contains("TEST-001"));
+            assert_eq!(cache.status().refreshes, 4);
+
+            let cache = Availability::new(Duration::from_secs(0));
+            cache.get(async { albums(&["TEST-001"]) }).await.unwrap();
+            // always expired
+            let result = cache.get(async { albums(&[]) }).await.unwrap();
+            assert!(result.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_single_flight() {
+        actix_web::rt::System::new().block_on(async {
+            let cache = Availability::new(Duration::from_secs(0));
+            cache.get(async { albums(&["TEST-001"]) }).await.unwrap();
+
+            let fetches = AtomicUsize::new(0);
+            let fetch = |catalogs: &'static [&'static str]| {
+                let fetches = &fetches;
+                async move {
+                    fetches.fetch_add(1, Ordering::SeqCst);
+                    actix_web::rt::time::sleep(Duration::from_millis(10)).await;
+                    albums(catalogs)
+                }
+            };
+            let (first, second) = futures_util::future::join(
+                cache.get(fetch(&["TEST-002"])),
+                cache.get(fetch(&["TEST-003"])),
+            ).await;
+            // the second request serves the expired copy instead of fetching again
+            assert_eq!(fetches.load(Ordering::SeqCst), 1);
+            assert!(first.unwrap().contains("TEST-002"));
+            assert!(second.unwrap().contains("TEST-001"));
+        });
+    }
+}
@@ -0,0 +1,312 @@
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::Duration;
+use futures_util::future::join_all;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde::Serialize;
+use crate::availability::{self, Availability};
+use crate::config::AnnilConfig;
+use crate::error::redact;
+use crate::store::now;
+
+/// An annil server, with its album list cached.
+pub struct Backend {
+    pub config: AnnilConfig,
+    /// Catalogs of albums and discs in this backend
+    albums: Availability,
+    /// Requests failed in a row, the backend is unhealthy if not zero
+    consecutive_failures: AtomicU32,
+    failures: AtomicU64,
+    /// Unix timestamp and message of the last error
+    last_error: Mutex<Option<(i64, String)>>,
+}
+
+impl Backend {
+    fn new(config: AnnilConfig) -> Self {
+        Self {
+            albums: Availability::new(Duration::from_secs(config.albums_ttl)),
+            config,
+            consecutive_failures: AtomicU32::new(0),
+            failures: AtomicU64::new(0),
+            last_error: Mutex::new(None),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.config.name()
+    }
+
+    async fn fetch_albums(&self) -> anyhow::Result<Vec<String>> {
+        let result = self.config.albums().await;
+        match &result {
+            Ok(_) => self.succeeded(),
+            Err(e) => self.failed(e),
+        }
+        result
+    }
+
+    /// Whether `catalog` is in the last fetched album list.
+    fn has(&self, catalog: &str) -> bool {
+        matches!(self.albums.cached(), Some(albums) if albums.contains(catalog))
+    }
+
+    fn succeeded(&self) {
+        if self.consecutive_failures.swap(0, Ordering::Relaxed) > 0 {
+            log::info!("Annil backend {} recovered", self.name());
+        }
+    }
+
+    fn failed<E: Display>(&self, error: E) {
+        // shown in `/status`, which must not expose the token
+        let error = redact(&error.to_string());
+        self.failures.fetch_add(1, Ordering::Relaxed);
+        if self.consecutive_failures.fetch_add(1, Ordering::Relaxed) == 0 {
+            log::warn!("Annil backend {} is unhealthy: {}", self.name(), error);
+        }
+        *self.last_error.lock().unwrap() = Some((now(), error));
+    }
+
+    fn status(&self) -> BackendStatus {
+        let last_error = self.last_error.lock().unwrap().clone();
+        BackendStatus {
+            name: self.name().to_string(),
+            priority: self.config.priority,
+            healthy: self.consecutive_failures.load(Ordering::Relaxed) == 0,
+            consecutive_failures: self.consecutive_failures.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            last_error_time: last_error.as_ref().map(|(time, _)| *time),
+            last_error: last_error.map(|(_, error)| error),
+            albums: self.albums.status(),
+        }
+    }
+}
+
+/// Health of an annil backend.
+#[derive(Serialize)]
+pub struct BackendStatus {
+    pub name: String,
+    pub priority: i32,
+    pub healthy: bool,
+    pub consecutive_failures: u32,
+    pub failures: u64,
+    pub last_error_time: Option<i64>,
+    pub last_error: Option<String>,
+    pub albums: availability::Status,
+}
+
+/// Annil servers merged into one library, in order of priority.
+pub struct Backends {
+    backends: Vec<Backend>,
+    merged: RwLock<Option<Merged>>,
+}
+
+/// Merged album set, with album sets of backends and generation of `map` it was built from.
+struct Merged {
+    generation: u64,
+    from: Vec<Arc<HashSet<String>>>,
+    albums: Arc<HashSet<String>>,
+}
+
+impl Backends {
+    /// Create backends from configs sorted by priority.
+    pub fn new(configs: Vec<AnnilConfig>) -> Self {
+        Self {
+            backends: configs.into_iter().map(Backend::new).collect(),
+            merged: RwLock::new(None),
+        }
+    }
+
+    /// Catalogs in any backend, refreshing expired album lists.
+    ///
+    /// Merged catalogs are converted by `map`, see [`crate::repo::RepoManager::available`].
+    /// `generation` changes whenever `map` does, see [`crate::repo::RepoManager::generation`].
+    /// Error is returned only if no backend has ever responded.
+    pub async fn albums<F>(&self, generation: u64, map: F) -> anyhow::Result<Arc<HashSet<String>>>
+        where F: FnOnce(Vec<String>) -> Vec<String>
+    {
+        let results = join_all(self.backends.iter().map(|backend| backend.albums.get(backend.fetch_albums()))).await;
+        self.merge(results, generation, map)
+    }
+
+    /// Refresh album lists of all backends now.
+    pub async fn refresh<F>(&self, generation: u64, map: F) -> anyhow::Result<Arc<HashSet<String>>>
+        where F: FnOnce(Vec<String>) -> Vec<String>
+    {
+        let results = join_all(self.backends.iter().map(|backend| backend.albums.refresh(backend.fetch_albums()))).await;
+        self.merge(results, generation, map)
+    }
+
+    fn merge<F>(&self, results: Vec<anyhow::Result<Arc<HashSet<String>>>>, generation: u64, map: F) -> anyhow::Result<Arc<HashSet<String>>>
+        where F: FnOnce(Vec<String>) -> Vec<String>
+    {
+        let mut sets = Vec::with_capacity(results.len());
+        let mut error = None;
+        for (backend, result) in self.backends.iter().zip(results) {
+            match result {
+                Ok(albums) => sets.push(albums),
+                Err(e) => {
+                    log::error!("Failed to get album list from annil backend {}: {}", backend.name(), redact(&e.to_string()));
+                    error = Some(e);
+                }
+            }
+        }
+        if sets.is_empty() {
+            return Err(error.unwrap_or_else(|| anyhow::anyhow!("No annil backend configured")));
+        }
+
+        // album lists rarely change, so merged set is reused until any of them is refreshed or `map` changes
+        if let Some(merged) = self.merged.read().unwrap().as_ref() {
+            if merged.generation == generation
+                && merged.from.len() == sets.len()
+                && merged.from.iter().zip(sets.iter()).all(|(a, b)| Arc::ptr_eq(a, b)) {
+                return Ok(merged.albums.clone());
+            }
+        }
+        let catalogs = sets.iter().flat_map(|albums| albums.iter().cloned()).collect::<HashSet<_>>();
+        let albums = Arc::new(map(catalogs.into_iter().collect()).into_iter().collect::<HashSet<_>>());
+        let mut merged = self.merged.write().unwrap();
+        // requests still holding a previous repository must not replace sets of the current one
+        if !matches!(&*merged, Some(merged) if merged.generation > generation) {
+            *merged = Some(Merged { generation, from: sets, albums: albums.clone() });
+        }
+        Ok(albums)
+    }
+
+    /// Backends to request `path` from, in order.
+    ///
+    /// Backends having the catalog of `path` are preferred, and all backends are tried if none has it.
+    pub fn route(&self, path: &str) -> Vec<&Backend> {
+        let catalog = path.split('/').next().unwrap_or(path);
+        let result: Vec<_> = self.backends.iter().filter(|backend| backend.has(catalog)).collect();
+        if result.is_empty() {
+            self.backends.iter().collect()
+        } else {
+            result
+        }
+    }
+
+    /// Request `path` from backends, falling back to the next backend on errors.
+    ///
+    /// `build` customizes the request for each backend.
+    /// If no backend has the file, the last `404 Not Found` response is returned.
+    pub async fn request<F>(&self, path: &str, build: F) -> anyhow::Result<Response>
+        where F: Fn(RequestBuilder) -> RequestBuilder
+    {
+        let mut not_found = None;
+        let mut error = None;
+        for backend in self.route(path) {
+            match build(backend.config.request(path)).send().await {
+                Ok(response) if response.status() == StatusCode::NOT_FOUND => {
+                    backend.succeeded();
+                    not_found = Some(response);
+                }
+                Ok(response) if response.status().is_server_error() => {
+                    let e = anyhow::anyhow!("{} responded with {}", path, response.status());
+                    backend.failed(&e);
+                    error = Some(e);
+                }
+                Ok(response) => {
+                    backend.succeeded();
+                    return Ok(response);
+                }
+                Err(e) => {
+                    // url contains the token, and callers log errors with `path` anyway
+                    let e = e.without_url();
+                    backend.failed(&e);
+                    error = Some(e.into());
+                }
+            }
+        }
+        match (not_found, error) {
+            (Some(response), _) => Ok(response),
+            (None, Some(e)) => Err(e),
+            (None, None) => anyhow::bail!("No annil backend configured"),
+        }
+    }
+
+    pub fn status(&self) -> Vec<BackendStatus> {
+        self.backends.iter().map(Backend::status).collect()
+    }
+
+    /// Metrics of backends in Prometheus text format.
+    pub fn metrics(&self) -> String {
+        let status = self.status();
+        let mut result = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: &dyn Fn(&BackendStatus) -> String| {
+            result += &format!("# HELP annisonic_{} {}\n# TYPE annisonic_{} {}\n", name, help, name, kind);
+            for backend in status.iter() {
+                result += &format!("annisonic_{}{{backend=\"{}\"}} {}\n", name, backend.name.replace('"', "\\\""), value(backend));
+            }
+        };
+        metric("annil_up", "gauge", "Whether the last request to annil backend succeeded.", &|b| (b.healthy as u8).to_string());
+        metric("annil_request_failures_total", "counter", "Failed requests to annil backend.", &|b| b.failures.to_string());
+        metric("annil_albums", "gauge", "Albums and discs available in annil backend.", &|b| b.albums.albums.to_string());
+        metric("annil_albums_refreshes_total", "counter", "Refreshes of album list of annil backend.", &|b| b.albums.refreshes.to_string());
+        metric("annil_albums_refresh_failures_total", "counter", "Failed refreshes of album list of annil backend.", &|b| b.albums.failures.to_string());
+        metric("annil_albums_last_success_timestamp_seconds", "gauge", "Time of the last successful refresh of album list of annil backend.", &|b| b.albums.last_success.unwrap_or(0).to_string());
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crate::backend::Backends;
+    use crate::config::AnnilConfig;
+    use crate::test_util;
+
+    /// Start an annil server having `albums`, or responding 500 to everything if `albums` is `None`.
+    fn mock_annil(albums: Option<&'static str>) -> AnnilConfig {
+        let (url, _) = test_util::mock_server(move |request| {
+            let path = test_util::path(request);
+            match albums {
+                None => (500, String::new()),
+                Some(albums) if path == "/albums" => (200, albums.to_string()),
+                Some(albums) if albums.contains(path.split('/').nth(1).unwrap()) => (200, path.to_string()),
+                Some(_) => (404, String::new()),
+            }
+        });
+        toml::from_str(&format!("server = \"{}\"\ntoken = \"token\"", url)).unwrap()
+    }
+
+    #[test]
+    fn test_backends() {
+        actix_web::rt::System::new().block_on(async {
+            let backends = Backends::new(vec![
+                mock_annil(None),
+                mock_annil(Some(r#"["TEST-001", "TEST-002"]"#)),
+                mock_annil(Some(r#"["TEST-002", "TEST-003"]"#)),
+            ]);
+            let albums = backends.albums(1, |catalogs| catalogs).await.unwrap();
+            let mut catalogs: Vec<_> = albums.iter().map(String::as_str).collect();
+            catalogs.sort_unstable();
+            assert_eq!(catalogs, vec!["TEST-001", "TEST-002", "TEST-003"]);
+
+            // merged set is reused in the same generation only, and never replaced by an older one
+            assert!(Arc::ptr_eq(&backends.albums(1, |_| Vec::new()).await.unwrap(), &albums));
+            assert!(backends.albums(2, |_| Vec::new()).await.unwrap().is_empty());
+            assert_eq!(backends.albums(1, |catalogs| catalogs).await.unwrap().len(), 3);
+            assert!(backends.albums(2, |catalogs| catalogs).await.unwrap().is_empty());
+
+            // routed to backends having the album only
+            let route: Vec<_> = backends.route("TEST-003/1").iter().map(|b| b.name().to_string()).collect();
+            assert_eq!(route, vec![backends.backends[2].name().to_string()]);
+            let response = backends.request("TEST-003/1", |r| r).await.unwrap();
+            assert_eq!(response.text().await.unwrap(), "/TEST-003/1");
+
+            // unknown catalog falls back to all backends, skipping the broken one
+            let response = backends.request("TEST-004/1", |r| r).await.unwrap();
+            assert_eq!(response.status().as_u16(), 404);
+
+            let status = backends.status();
+            assert!(!status[0].healthy);
+            // error response of album list is a failure, and the token is not exposed
+            let error = status[0].last_error.as_deref().unwrap();
+            assert!(error.contains("500") && !error.contains("auth="), "{}", error);
+            assert!(status[1].healthy && status[2].healthy);
+            assert!(backends.metrics().contains("annisonic_annil_up{backend=\"http://127.0.0.1"));
+        });
+    }
+}
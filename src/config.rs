@@ -1,12 +1,27 @@
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 use std::path::Path;
 use std::fs;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
 
 #[derive(Deserialize)]
 pub struct Config {
     pub server: ServerConfig,
     pub repo: RepoConfig,
-    pub annil: AnnilConfig,
+    /// Legacy single annil server, prefer `[[backends]]` instead
+    pub annil: Option<AnnilConfig>,
+    /// Annil servers, merged into one library
+    #[serde(default)]
+    pub backends: Vec<AnnilConfig>,
+    #[serde(default)]
+    pub transcode: TranscodeConfig,
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
+    #[serde(default)]
+    pub store: StoreConfig,
+    #[serde(default)]
+    pub cover: CoverConfig,
 }
 
 impl Config {
@@ -15,13 +30,41 @@ impl Config {
         let result = toml::from_str(&string)?;
         Ok(result)
     }
+
+    /// Annil servers in order of priority, highest first.
+    pub fn backends(&self) -> Vec<AnnilConfig> {
+        let mut backends: Vec<_> = self.backends.iter().chain(self.annil.iter()).cloned().collect();
+        // stable sort keeps the order in config for equal priorities
+        backends.sort_by_key(|backend| std::cmp::Reverse(backend.priority));
+        backends
+    }
 }
 
 #[derive(Deserialize)]
 pub struct ServerConfig {
     listen: Option<String>,
-    pub username: String,
-    pub password: String,
+    /// Path prefix when served under a sub path by reverse proxy, like `/music`
+    #[serde(default)]
+    base_path: String,
+    /// Reverse proxies whose forwarded client address and scheme are trusted, like `127.0.0.1`
+    #[serde(default)]
+    pub trusted_proxies: Vec<Network>,
+    /// Legacy single user, prefer `[[users]]` instead
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Minutes after which a song is no longer reported by `getNowPlaying`
+    #[serde(default = "default_now_playing_timeout")]
+    pub now_playing_timeout: u64,
+    /// Allow token authentication with `t` and `s`, which is an md5 hash of password and salt
+    #[serde(default = "default_token_auth")]
+    pub token_auth: bool,
+    #[serde(default)]
+    pub lockout: LockoutConfig,
+    /// Songs probed in annil at once for track information, like duration and size
+    #[serde(default = "default_probe_concurrency")]
+    pub probe_concurrency: usize,
+    /// Serve HTTPS, in addition to or instead of plain HTTP on `listen`
+    pub tls: Option<TlsConfig>,
 }
 
 impl ServerConfig {
@@ -32,20 +75,342 @@ impl ServerConfig {
             default
         }
     }
+
+    /// `base_path` with leading slash and without trailing slash, empty for root.
+    pub fn base_path(&self) -> String {
+        match self.base_path.trim_matches('/') {
+            "" => String::new(),
+            path => format!("/{}", path),
+        }
+    }
+}
+
+fn default_now_playing_timeout() -> u64 {
+    10
+}
+
+fn default_token_auth() -> bool {
+    true
+}
+
+fn default_probe_concurrency() -> usize {
+    8
+}
+
+#[derive(Deserialize)]
+pub struct TlsConfig {
+    #[serde(default = "default_tls_listen")]
+    pub listen: String,
+    /// Path to PEM encoded certificate chain
+    pub cert: String,
+    /// Path to PEM encoded private key
+    pub key: String,
+    /// What to do with plain HTTP listener on `server.listen`
+    #[serde(default)]
+    pub http: PlainHttp,
+    /// Host names of server like `music.example.com`, which plain HTTP requests may be redirected to
+    ///
+    /// Requests are redirected to the one in `Host` header if listed, or the first one otherwise.
+    #[serde(default)]
+    pub server_names: Vec<String>,
+}
+
+impl TlsConfig {
+    /// Port of HTTPS listener, which plain HTTP requests are redirected to.
+    pub fn port(&self) -> anyhow::Result<u16> {
+        match self.listen.rsplit(':').next().and_then(|port| port.parse().ok()) {
+            Some(port) => Ok(port),
+            None => anyhow::bail!("Invalid tls listen address {}", self.listen),
+        }
+    }
+}
+
+fn default_tls_listen() -> String {
+    "0.0.0.0:1711".to_string()
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PlainHttp {
+    /// Serve the same api as HTTPS
+    #[default]
+    Serve,
+    /// Redirect all requests to HTTPS
+    Redirect,
+    /// Do not listen on plain HTTP
+    Disabled,
+}
+
+/// Lockout of clients and users after failed logins, see [`crate::lockout::Lockout`].
+#[derive(Deserialize, Clone)]
+pub struct LockoutConfig {
+    /// Failed logins allowed before locking out, 0 to disable lockout
+    #[serde(default = "default_max_failures")]
+    pub max_failures: u32,
+    /// Seconds of the first lockout, doubled on each further failure
+    #[serde(default = "default_lockout")]
+    pub lockout: i64,
+    /// Maximum seconds of lockout, failures are also forgotten after this long
+    #[serde(default = "default_max_lockout")]
+    pub max_lockout: i64,
+    /// Trusted networks which are never locked out, like `192.168.0.0/16`
+    #[serde(default)]
+    pub allowlist: Vec<Network>,
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        Self {
+            max_failures: default_max_failures(),
+            lockout: default_lockout(),
+            max_lockout: default_max_lockout(),
+            allowlist: Vec::new(),
+        }
+    }
+}
+
+fn default_max_failures() -> u32 {
+    5
+}
+
+fn default_lockout() -> i64 {
+    60
+}
+
+fn default_max_lockout() -> i64 {
+    3600
+}
+
+/// A range of addresses in CIDR notation, or a single address.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // xor of addresses must be zero in prefix bits
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => (u32::from(net) ^ u32::from(ip)).checked_shr(32 - self.prefix as u32).unwrap_or(0) == 0,
+            (IpAddr::V6(net), IpAddr::V6(ip)) => (u128::from(net) ^ u128::from(ip)).checked_shr(128 - self.prefix as u32).unwrap_or(0) == 0,
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>()?, Some(prefix.parse::<u8>()?)),
+            None => (s.parse::<IpAddr>()?, None),
+        };
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        match prefix {
+            Some(prefix) if prefix > max => anyhow::bail!("Invalid prefix length in {}", s),
+            prefix => Ok(Self { addr, prefix: prefix.unwrap_or(max) }),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Network {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Stream,
+    Download,
+    Playlist,
+    Admin,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct UserConfig {
+    pub username: String,
+    pub password: String,
+    #[serde(default = "default_roles")]
+    pub roles: Vec<Role>,
+    /// Music folders the user is allowed to access
+    #[serde(default = "default_folders")]
+    pub folders: Vec<String>,
+    /// Services to forward scrobbles to
+    #[serde(default)]
+    pub scrobble: Vec<ScrobbleConfig>,
+}
+
+impl UserConfig {
+    pub fn has_role(&self, role: Role) -> bool {
+        // admin can do anything
+        self.roles.contains(&role) || self.roles.contains(&Role::Admin)
+    }
+
+    pub fn can_access_folder(&self, folder: &str) -> bool {
+        self.folders.iter().any(|f| f == folder)
+    }
+}
+
+fn default_roles() -> Vec<Role> {
+    vec![Role::Stream]
+}
+
+fn default_folders() -> Vec<String> {
+    vec!["@".to_string()]
+}
+
+/// A ListenBrainz or Last.fm compatible scrobbling service.
+#[derive(Deserialize, Clone)]
+#[serde(tag = "service", rename_all = "lowercase")]
+pub enum ScrobbleConfig {
+    ListenBrainz {
+        #[serde(default = "default_listenbrainz")]
+        url: String,
+        /// User token from ListenBrainz settings
+        token: String,
+    },
+    LastFm {
+        #[serde(default = "default_lastfm")]
+        url: String,
+        api_key: String,
+        api_secret: String,
+        /// Session key obtained from `auth.getSession`
+        session_key: String,
+    },
+}
+
+impl ScrobbleConfig {
+    /// Api endpoint, which also identifies the service in retry queue.
+    pub fn url(&self) -> &str {
+        match self {
+            ScrobbleConfig::ListenBrainz { url, .. } | ScrobbleConfig::LastFm { url, .. } => url,
+        }
+    }
+}
+
+fn default_listenbrainz() -> String {
+    "https://api.listenbrainz.org".to_string()
+}
+
+fn default_lastfm() -> String {
+    "https://ws.audioscrobbler.com/2.0/".to_string()
 }
 
 #[derive(Deserialize)]
+pub struct StoreConfig {
+    /// Path to sqlite database
+    pub path: String,
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        Self { path: "annisonic.db".to_string() }
+    }
+}
+
+/// Covers returned by `getCoverArt`, scaled if `size` is given.
+#[derive(Deserialize, Clone)]
+pub struct CoverConfig {
+    /// Directory to cache resized covers in
+    #[serde(default = "default_cover_cache_dir")]
+    pub cache_dir: String,
+    /// Maximum size of cache in MiB, least recently used covers are removed first
+    #[serde(default = "default_cover_cache_size")]
+    pub cache_size: u64,
+    #[serde(default)]
+    pub format: CoverFormat,
+    /// JPEG quality from 1 to 100
+    #[serde(default = "default_cover_quality")]
+    pub quality: u8,
+}
+
+impl Default for CoverConfig {
+    fn default() -> Self {
+        Self {
+            cache_dir: default_cover_cache_dir(),
+            cache_size: default_cover_cache_size(),
+            format: CoverFormat::default(),
+            quality: default_cover_quality(),
+        }
+    }
+}
+
+fn default_cover_cache_dir() -> String {
+    "covers".to_string()
+}
+
+fn default_cover_cache_size() -> u64 {
+    256
+}
+
+fn default_cover_quality() -> u8 {
+    85
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CoverFormat {
+    #[default]
+    Jpeg,
+    /// Lossless WebP, which is larger than JPEG for most covers
+    Webp,
+}
+
+impl CoverFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            CoverFormat::Jpeg => "jpg",
+            CoverFormat::Webp => "webp",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            CoverFormat::Jpeg => "image/jpeg",
+            CoverFormat::Webp => "image/webp",
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
 pub struct RepoConfig {
     pub root: String,
+    /// Present each disc of multi-disc albums as an album
+    #[serde(default)]
+    pub split_discs: bool,
 }
 
 #[derive(Deserialize, Clone)]
 pub struct AnnilConfig {
+    /// Name shown in logs and status, defaults to server url
+    name: Option<String>,
     server: String,
     token: String,
+    /// Backends with higher priority are requested first
+    #[serde(default)]
+    pub priority: i32,
+    /// Redirect clients to annil instead of proxying.
+    ///
+    /// Annil token would be exposed to clients in redirected url.
+    #[serde(default)]
+    pub redirect: bool,
+    /// Seconds to cache album list of annil
+    #[serde(default = "default_albums_ttl")]
+    pub albums_ttl: u64,
+    #[serde(skip)]
+    client: reqwest::Client,
 }
 
 impl AnnilConfig {
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or_else(|| self.server())
+    }
+
     // removing padding '/'
     fn server(&self) -> &str {
         if self.server.ends_with("/") {
@@ -56,12 +421,91 @@ impl AnnilConfig {
     }
 
     pub async fn albums(&self) -> anyhow::Result<Vec<String>> {
-        let r = reqwest::get(format!("{}/albums?auth={}", self.server(), self.token)).await?;
+        // error responses are failures of the backend, not album lists failing to decode
+        let r = self.request("albums").send().await.and_then(|r| r.error_for_status()).map_err(reqwest::Error::without_url)?;
         Ok(r.json().await?)
     }
 
+    pub fn request(&self, middle: &str) -> reqwest::RequestBuilder {
+        self.client.get(self.get_url(middle))
+    }
+
     pub fn get_url(&self, middle: &str) -> String {
         format!("{}/{}?auth={}", self.server(), middle, self.token)
     }
 }
 
+fn default_albums_ttl() -> u64 {
+    300
+}
+
+#[derive(Deserialize, Clone)]
+pub struct TranscodeConfig {
+    /// Path to ffmpeg executable
+    #[serde(default = "default_ffmpeg")]
+    pub ffmpeg: String,
+    /// Format used when client only limits bitrate
+    #[serde(default = "default_format")]
+    pub default_format: String,
+    /// Transcoding profiles, keyed by the `format` requested by client
+    #[serde(default = "default_profiles")]
+    pub profiles: HashMap<String, TranscodeProfile>,
+}
+
+impl Default for TranscodeConfig {
+    fn default() -> Self {
+        Self {
+            ffmpeg: default_ffmpeg(),
+            default_format: default_format(),
+            profiles: default_profiles(),
+        }
+    }
+}
+
+impl TranscodeConfig {
+    pub fn profile(&self, format: &str) -> Option<&TranscodeProfile> {
+        self.profiles.get(format)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct TranscodeProfile {
+    /// ffmpeg audio encoder
+    pub codec: String,
+    /// ffmpeg output format
+    pub container: String,
+    pub content_type: String,
+    /// Bitrate in kbps when client does not specify `maxBitRate`
+    pub bitrate: u32,
+    /// Extra ffmpeg output arguments
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+impl TranscodeProfile {
+    fn new(codec: &str, container: &str, content_type: &str, bitrate: u32) -> Self {
+        Self {
+            codec: codec.to_string(),
+            container: container.to_string(),
+            content_type: content_type.to_string(),
+            bitrate,
+            args: Vec::new(),
+        }
+    }
+}
+
+fn default_ffmpeg() -> String {
+    "ffmpeg".to_string()
+}
+
+fn default_format() -> String {
+    "opus".to_string()
+}
+
+fn default_profiles() -> HashMap<String, TranscodeProfile> {
+    let mut profiles = HashMap::new();
+    profiles.insert("opus".to_string(), TranscodeProfile::new("libopus", "ogg", "audio/ogg", 128));
+    profiles.insert("mp3".to_string(), TranscodeProfile::new("libmp3lame", "mp3", "audio/mpeg", 320));
+    profiles.insert("aac".to_string(), TranscodeProfile::new("aac", "adts", "audio/aac", 256));
+    profiles
+}
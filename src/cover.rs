@@ -0,0 +1,356 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use actix_web::{HttpRequest, HttpResponse};
+use actix_web::http::header;
+use image::{ColorType, DynamicImage, Rgb, RgbImage};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use reqwest::StatusCode;
+use crate::backend::Backends;
+use crate::config::{CoverConfig, CoverFormat};
+
+/// Sizes covers are scaled to, so that clients asking for slightly different sizes share the cache.
+const BUCKETS: [u32; 6] = [64, 128, 256, 512, 768, 1024];
+
+/// Cached covers are immutable for a path and size, so clients may keep them for a week.
+const CACHE_CONTROL: &str = "public, max-age=604800";
+
+/// Smallest bucket not smaller than `size`, or the largest bucket.
+fn bucket(size: u32) -> u32 {
+    BUCKETS.iter().copied().find(|bucket| *bucket >= size).unwrap_or(BUCKETS[BUCKETS.len() - 1])
+}
+
+struct Entry {
+    size: u64,
+    /// Tick of the last access, smaller ones are evicted first
+    used: u64,
+}
+
+/// Files in cache directory, with their last access.
+#[derive(Default)]
+struct Index {
+    entries: HashMap<String, Entry>,
+    total: u64,
+    clock: u64,
+}
+
+impl Index {
+    /// Mark `key` as used, returns whether it is cached.
+    fn touch(&mut self, key: &str) -> bool {
+        self.clock += 1;
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                entry.used = self.clock;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Add `key` of `size` bytes, returns keys evicted to keep total size under `limit`.
+    fn insert(&mut self, key: String, size: u64, limit: u64) -> Vec<String> {
+        self.clock += 1;
+        if let Some(old) = self.entries.insert(key, Entry { size, used: self.clock }) {
+            self.total -= old.size;
+        }
+        self.total += size;
+
+        let mut evicted = Vec::new();
+        while self.total > limit {
+            let oldest = match self.entries.iter().min_by_key(|(_, entry)| entry.used) {
+                Some((key, _)) => key.clone(),
+                None => break,
+            };
+            let entry = self.entries.remove(&oldest).unwrap();
+            self.total -= entry.size;
+            evicted.push(oldest);
+        }
+        evicted
+    }
+}
+
+/// Covers scaled to the size requested by clients, cached on disk.
+///
+/// Albums without cover get a placeholder, so that clients do not keep retrying.
+pub struct Covers {
+    config: CoverConfig,
+    dir: PathBuf,
+    index: Mutex<Index>,
+    /// Counter for names of temporary files
+    writes: AtomicU64,
+}
+
+impl Covers {
+    /// Open cache directory, files in it are ordered by modification time for eviction.
+    ///
+    /// Temporary files left by interrupted writes are removed.
+    pub fn new(config: CoverConfig) -> anyhow::Result<Self> {
+        let dir = PathBuf::from(&config.cache_dir);
+        fs::create_dir_all(&dir)?;
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !metadata.is_file() {
+                continue;
+            }
+            if name.starts_with('.') {
+                fs::remove_file(entry.path())?;
+                continue;
+            }
+            files.push((metadata.modified()?, name, metadata.len()));
+        }
+        files.sort();
+
+        let mut index = Index::default();
+        for (_, key, size) in files {
+            index.clock += 1;
+            index.total += size;
+            index.entries.insert(key, Entry { size, used: index.clock });
+        }
+        Ok(Self { config, dir, index: Mutex::new(index), writes: AtomicU64::new(0) })
+    }
+
+    fn limit(&self) -> u64 {
+        self.config.cache_size * 1024 * 1024
+    }
+
+    fn read(&self, key: &str) -> Option<Vec<u8>> {
+        if !self.index.lock().unwrap().touch(key) {
+            return None;
+        }
+        fs::read(self.dir.join(key)).ok()
+    }
+
+    fn write(&self, key: &str, data: &[u8]) {
+        // written to a temporary file first, so that readers never see partial files,
+        // which is unique as concurrent requests may write the same cover
+        let id = self.writes.fetch_add(1, Ordering::Relaxed);
+        let tmp = self.dir.join(format!(".{}.{}.{}", key, std::process::id(), id));
+        if let Err(e) = fs::write(&tmp, data).and_then(|_| fs::rename(&tmp, self.dir.join(key))) {
+            log::warn!("Failed to cache cover {}: {}", key, e);
+            return;
+        }
+        let evicted = self.index.lock().unwrap().insert(key.to_string(), data.len() as u64, self.limit());
+        for key in evicted {
+            if let Err(e) = fs::remove_file(self.dir.join(&key)) {
+                log::warn!("Failed to remove cached cover {}: {}", key, e);
+            }
+        }
+    }
+
+    /// Serve cover at `path` on annil, scaled to fit in `size`, or the original one if `size` is `None`.
+    pub async fn serve(&self, backends: &Backends, path: &str, size: Option<u32>, req: &HttpRequest) -> HttpResponse {
+        let size = match size {
+            Some(size) => bucket(size),
+            None => return self.original(backends, path, req).await,
+        };
+        let format = self.config.format;
+        let key = format!("{:x}-{}.{}", md5::compute(path), size, format.extension());
+        if let Some(data) = self.read(&key) {
+            return respond(req, &key, format.content_type(), data);
+        }
+
+        let original = match fetch(backends, path).await {
+            Ok(Some(original)) => original,
+            Ok(None) => return self.placeholder(size, req).await,
+            Err(e) => {
+                log::error!("Failed to fetch cover {} from annil: {}", path, e);
+                return HttpResponse::BadGateway().finish();
+            }
+        };
+        let quality = self.config.quality;
+        match blocking(move || scale(&original, size, format, quality)).await {
+            Ok(data) => {
+                self.write(&key, &data);
+                respond(req, &key, format.content_type(), data)
+            }
+            // not cached, so that the cover is served once it is fixed on annil
+            Err(e) => {
+                log::warn!("Failed to scale cover {}: {}", path, e);
+                self.placeholder(size, req).await
+            }
+        }
+    }
+
+    /// Original cover as is, cached like scaled ones.
+    async fn original(&self, backends: &Backends, path: &str, req: &HttpRequest) -> HttpResponse {
+        // format of original covers is unknown before fetching, so it is guessed from content instead of key
+        let key = format!("{:x}-original", md5::compute(path));
+        if let Some(data) = self.read(&key) {
+            if let Ok(format) = image::guess_format(&data) {
+                return respond(req, &key, format.to_mime_type(), data);
+            }
+        }
+
+        let original = match fetch(backends, path).await {
+            Ok(Some(original)) => original,
+            Ok(None) => return self.placeholder(bucket(u32::MAX), req).await,
+            Err(e) => {
+                log::error!("Failed to fetch cover {} from annil: {}", path, e);
+                return HttpResponse::BadGateway().finish();
+            }
+        };
+        match image::guess_format(&original) {
+            Ok(format) => {
+                self.write(&key, &original);
+                respond(req, &key, format.to_mime_type(), original)
+            }
+            Err(e) => {
+                log::warn!("Failed to recognize cover {}: {}", path, e);
+                self.placeholder(bucket(u32::MAX), req).await
+            }
+        }
+    }
+
+    /// Placeholder of `size`, shared by all albums without cover.
+    async fn placeholder(&self, size: u32, req: &HttpRequest) -> HttpResponse {
+        let format = self.config.format;
+        let key = format!("placeholder-{}.{}", size, format.extension());
+        if let Some(data) = self.read(&key) {
+            return respond(req, &key, format.content_type(), data);
+        }
+
+        let quality = self.config.quality;
+        match blocking(move || draw_placeholder(size, format, quality)).await {
+            Ok(data) => {
+                self.write(&key, &data);
+                respond(req, &key, format.content_type(), data)
+            }
+            Err(e) => {
+                log::error!("Failed to draw cover placeholder: {}", e);
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
+}
+
+/// Original cover, or `None` if the album has no cover.
+async fn fetch(backends: &Backends, path: &str) -> anyhow::Result<Option<Vec<u8>>> {
+    let response = backends.request(path, |request| request).await?;
+    match response.status() {
+        StatusCode::NOT_FOUND => Ok(None),
+        status if status.is_success() => Ok(Some(response.bytes().await?.to_vec())),
+        status => anyhow::bail!("{} responded with {}", path, status),
+    }
+}
+
+/// Run image processing on the blocking thread pool, as it would stall other requests.
+async fn blocking<F>(f: F) -> image::ImageResult<Vec<u8>>
+    where F: FnOnce() -> image::ImageResult<Vec<u8>> + Send + 'static
+{
+    tokio::task::spawn_blocking(f).await
+        .unwrap_or_else(|e| Err(io::Error::other(e).into()))
+}
+
+/// Scale `original` down to fit in `size`.
+fn scale(original: &[u8], size: u32, format: CoverFormat, quality: u8) -> image::ImageResult<Vec<u8>> {
+    let image = image::load_from_memory(original)?;
+    let image = if image.width() > size || image.height() > size {
+        image.resize(size, size, FilterType::Lanczos3)
+    } else {
+        image
+    };
+    encode(&image, format, quality)
+}
+
+fn draw_placeholder(size: u32, format: CoverFormat, quality: u8) -> image::ImageResult<Vec<u8>> {
+    let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(size, size, Rgb([0x9e, 0x9e, 0x9e])));
+    encode(&image, format, quality)
+}
+
+fn encode(image: &DynamicImage, format: CoverFormat, quality: u8) -> image::ImageResult<Vec<u8>> {
+    let image = image.to_rgb8();
+    let mut data = Vec::new();
+    match format {
+        CoverFormat::Jpeg => JpegEncoder::new_with_quality(&mut data, quality)
+            .encode(&image, image.width(), image.height(), ColorType::Rgb8)?,
+        CoverFormat::Webp => WebPEncoder::new_lossless(&mut data)
+            .encode(&image, image.width(), image.height(), ColorType::Rgb8)?,
+    }
+    Ok(data)
+}
+
+fn respond(req: &HttpRequest, key: &str, content_type: &str, data: Vec<u8>) -> HttpResponse {
+    let etag = format!("\"{}\"", key);
+    let not_modified = req.headers().get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| value.split(',').any(|tag| tag.trim() == etag || tag.trim() == "*"))
+        .is_some();
+    let mut response = if not_modified {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    response
+        .insert_header((header::ETAG, etag))
+        .insert_header((header::CACHE_CONTROL, CACHE_CONTROL));
+    if not_modified {
+        return response.finish();
+    }
+    response.content_type(content_type).body(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::config::{CoverConfig, CoverFormat};
+    use crate::cover::{bucket, draw_placeholder, scale, Covers, Index};
+
+    #[test]
+    fn test_bucket() {
+        assert_eq!(bucket(1), 64);
+        assert_eq!(bucket(128), 128);
+        assert_eq!(bucket(300), 512);
+        assert_eq!(bucket(4096), 1024);
+    }
+
+    #[test]
+    fn test_index() {
+        let mut index = Index::default();
+        assert!(index.insert("a".to_string(), 40, 100).is_empty());
+        assert!(index.insert("b".to_string(), 40, 100).is_empty());
+        assert!(index.touch("a"));
+        // b is the least recently used one
+        assert_eq!(index.insert("c".to_string(), 40, 100), vec!["b"]);
+        assert!(!index.touch("b"));
+        assert_eq!(index.total, 80);
+    }
+
+    #[test]
+    fn test_open() {
+        let dir = std::env::temp_dir().join(format!("annisonic-covers-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("cover-64.jpg"), [0; 10]).unwrap();
+        // left by an interrupted write
+        fs::write(dir.join(".cover-128.jpg.1.0"), [0; 20]).unwrap();
+
+        let covers = Covers::new(CoverConfig { cache_dir: dir.to_string_lossy().into_owned(), ..Default::default() }).unwrap();
+        assert_eq!(covers.index.lock().unwrap().total, 10);
+        assert!(!dir.join(".cover-128.jpg.1.0").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_scale() {
+        let placeholder = draw_placeholder(64, CoverFormat::Jpeg, 85).unwrap();
+        let image = image::load_from_memory(&placeholder).unwrap();
+        assert_eq!((image.width(), image.height()), (64, 64));
+
+        // aspect ratio is kept when scaling down
+        let wide = image::DynamicImage::new_rgb8(400, 200);
+        let mut original = Vec::new();
+        wide.write_to(&mut std::io::Cursor::new(&mut original), image::ImageOutputFormat::Png).unwrap();
+        let scaled = scale(&original, 128, CoverFormat::Webp, 85).unwrap();
+        let image = image::load_from_memory(&scaled).unwrap();
+        assert_eq!((image.width(), image.height()), (128, 64));
+
+        assert!(scale(b"not an image", 128, CoverFormat::Jpeg, 85).is_err());
+    }
+}
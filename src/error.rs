@@ -0,0 +1,180 @@
+use std::fmt;
+use actix_web::{dev, HttpResponse, Responder, ResponseError};
+use actix_web::body::AnyBody;
+use actix_web::http::StatusCode;
+use actix_web::middleware::ErrorHandlerResponse;
+use crate::models::SonicError;
+use crate::response;
+
+/// Errors returned to clients in the `<error>` envelope of subsonic.
+#[derive(Debug)]
+pub enum Error {
+    /// A required parameter is missing
+    MissingParameter(&'static str),
+    /// A parameter can not be parsed
+    InvalidParameter(String),
+    /// Client must upgrade to a newer protocol version
+    ClientTooOld,
+    /// Server must upgrade to a newer protocol version
+    ServerTooOld,
+    WrongCredentials,
+    /// Token authentication is disabled in config
+    TokenAuthNotSupported,
+    AuthMechanismNotSupported,
+    /// More than one of `apiKey`, `p` and `t` is given
+    ConflictingAuthMechanisms,
+    InvalidApiKey,
+    /// Too many failed logins, with seconds to wait
+    LockedOut(i64),
+    /// User is not authorized for the given operation
+    NotAuthorized(&'static str),
+    /// The requested data was not found
+    NotFound(String),
+    Generic(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Error of `what` not found, like `Album not found`.
+    pub fn not_found(what: &str) -> Self {
+        Error::NotFound(format!("{} not found", what))
+    }
+
+    pub fn code(&self) -> u32 {
+        match self {
+            Error::MissingParameter(_) | Error::InvalidParameter(_) => 10,
+            Error::ClientTooOld => 20,
+            Error::ServerTooOld => 30,
+            Error::WrongCredentials => 40,
+            Error::TokenAuthNotSupported => 41,
+            Error::AuthMechanismNotSupported => 42,
+            Error::ConflictingAuthMechanisms => 43,
+            Error::InvalidApiKey => 44,
+            Error::NotAuthorized(_) => 50,
+            Error::NotFound(_) => 70,
+            Error::LockedOut(_) | Error::Generic(_) => 0,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::MissingParameter(name) => write!(f, "Required parameter is missing: {}", name),
+            Error::InvalidParameter(message) => write!(f, "Invalid parameter: {}", message),
+            Error::ClientTooOld => write!(f, "Incompatible Subsonic REST protocol version. Client must upgrade."),
+            Error::ServerTooOld => write!(f, "Incompatible Subsonic REST protocol version. Server must upgrade."),
+            Error::WrongCredentials => write!(f, "Wrong username or password"),
+            Error::TokenAuthNotSupported => write!(f, "Token authentication not supported"),
+            Error::AuthMechanismNotSupported => write!(f, "Provided authentication mechanism not supported"),
+            Error::ConflictingAuthMechanisms => write!(f, "Multiple conflicting authentication mechanisms provided"),
+            Error::InvalidApiKey => write!(f, "Invalid API key"),
+            Error::LockedOut(wait) => write!(f, "Too many failed logins, try again in {} seconds", wait),
+            Error::NotAuthorized(message) => write!(f, "{}", message),
+            Error::NotFound(message) | Error::Generic(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        log::error!("Store error: {}", e);
+        Error::Generic("Failed to access local store".to_string())
+    }
+}
+
+/// Details are logged only, as errors of annil requests contain urls with the token.
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Self {
+        log::error!("{}", redact(&format!("{:#}", e)));
+        Error::Generic("Internal server error".to_string())
+    }
+}
+
+/// `message` with values of `auth` parameters hidden, which are annil tokens in urls.
+pub fn redact(message: &str) -> String {
+    let mut result = String::with_capacity(message.len());
+    let mut rest = message;
+    while let Some(i) = rest.find("auth=") {
+        let (head, tail) = rest.split_at(i + "auth=".len());
+        result.push_str(head);
+        result.push_str("<redacted>");
+        rest = tail.trim_start_matches(|c: char| !c.is_whitespace() && !matches!(c, '&' | ')' | '"' | '\''));
+    }
+    result.push_str(rest);
+    result
+}
+
+impl ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+
+    /// The error is kept in extensions, and rendered by [`render`] in the format client requested.
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::new(self.status_code());
+        response.extensions_mut().insert(SonicError { code: self.code(), message: self.to_string() });
+        response
+    }
+}
+
+/// Render errors as `200 OK` with `status="failed"`, which is what subsonic clients expect.
+///
+/// Other internal server errors are rendered as generic errors.
+pub fn render(res: dev::ServiceResponse) -> actix_web::Result<ErrorHandlerResponse<AnyBody>> {
+    let (code, message) = match res.response().extensions().get::<SonicError>() {
+        Some(error) => (error.code, error.message.clone()),
+        None => (0, "Internal server error".to_string()),
+    };
+    let response = response::failed(code, message).respond_to(res.request());
+    Ok(ErrorHandlerResponse::Response(res.into_response(response)))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{web, App, HttpResponse};
+    use actix_web::test::{init_service, call_service, read_body_json, TestRequest};
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::ErrorHandlers;
+    use crate::error::{self, Error};
+
+    async fn missing() -> error::Result<HttpResponse> {
+        Err(Error::not_found("Album"))
+    }
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(
+            error::redact("error sending request for url (http://annil/TEST-001/1?auth=secret.token): timed out"),
+            "error sending request for url (http://annil/TEST-001/1?auth=<redacted>): timed out",
+        );
+        assert_eq!(error::redact("http://annil/albums?auth=a&x=1 and ?auth=b"), "http://annil/albums?auth=<redacted>&x=1 and ?auth=<redacted>");
+        assert_eq!(error::redact("no url"), "no url");
+    }
+
+    #[test]
+    fn test_anyhow() {
+        let error = Error::from(anyhow::anyhow!("http://annil/albums?auth=secret responded with 500"));
+        assert_eq!(error.code(), 0);
+        assert_eq!(error.to_string(), "Internal server error");
+    }
+
+    #[test]
+    fn test_render() {
+        actix_web::rt::System::new().block_on(async {
+            let app = init_service(App::new()
+                .wrap(ErrorHandlers::new().handler(StatusCode::INTERNAL_SERVER_ERROR, error::render))
+                .route("/getAlbum.view", web::get().to(missing))
+            ).await;
+            let req = TestRequest::get().uri("/getAlbum.view?f=json").to_request();
+            let res = call_service(&app, req).await;
+            // subsonic clients expect errors in 200 OK
+            assert_eq!(res.status(), StatusCode::OK);
+            let body: serde_json::Value = read_body_json(res).await;
+            assert_eq!(body["subsonic-response"]["status"], "failed");
+            assert_eq!(body["subsonic-response"]["error"]["code"], 70);
+            assert_eq!(body["subsonic-response"]["error"]["message"], "Album not found");
+        });
+    }
+}
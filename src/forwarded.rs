@@ -0,0 +1,131 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use actix_web::dev::RequestHead;
+use actix_web::http::HeaderMap;
+use crate::config::Network;
+
+/// Address and scheme of a client, as seen by the first trusted proxy.
+pub struct Peer {
+    pub ip: Option<IpAddr>,
+    pub scheme: String,
+}
+
+/// Reverse proxies whose `Forwarded`, `X-Forwarded-For` and `X-Forwarded-Proto` headers are trusted.
+///
+/// Headers from other clients are ignored, as anyone can send them.
+#[derive(Clone)]
+pub struct Proxies {
+    trusted: Arc<Vec<Network>>,
+}
+
+impl Proxies {
+    pub fn new(trusted: Vec<Network>) -> Self {
+        Self { trusted: Arc::new(trusted) }
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|net| net.contains(ip))
+    }
+
+    /// Real client of request, found by walking forwarded addresses from the nearest proxy until an untrusted one.
+    ///
+    /// `secure` tells whether the request is received by HTTPS listener.
+    pub fn resolve(&self, head: &RequestHead, secure: bool) -> Peer {
+        let scheme = if secure { "https" } else { "http" };
+        let mut peer = Peer { ip: head.peer_addr.map(|addr| addr.ip()), scheme: scheme.to_string() };
+        for (ip, proto) in hops(&head.headers).into_iter().rev() {
+            match peer.ip {
+                Some(current) if self.is_trusted(current) => {
+                    // obfuscated or unknown addresses can not be resolved further
+                    peer.ip = match ip {
+                        Some(ip) => Some(ip),
+                        None => break,
+                    };
+                    if let Some(proto) = proto {
+                        peer.scheme = proto;
+                    }
+                }
+                _ => break,
+            }
+        }
+        peer
+    }
+}
+
+/// Forwarded client addresses with protocols, from the original client to the nearest proxy.
+///
+/// `Forwarded` is preferred over `X-Forwarded-*` if both are present.
+fn hops(headers: &HeaderMap) -> Vec<(Option<IpAddr>, Option<String>)> {
+    let values = |name| headers.get_all(name)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|value| value.trim())
+        .collect::<Vec<_>>();
+
+    let forwarded = values("forwarded");
+    if !forwarded.is_empty() {
+        return forwarded.into_iter().map(|element| {
+            let mut hop = (None, None);
+            for pair in element.split(';') {
+                if let Some((key, value)) = pair.split_once('=') {
+                    let value = value.trim().trim_matches('"');
+                    match key.trim().to_ascii_lowercase().as_str() {
+                        "for" => hop.0 = parse_node(value),
+                        "proto" => hop.1 = Some(value.to_ascii_lowercase()),
+                        _ => {}
+                    }
+                }
+            }
+            hop
+        }).collect();
+    }
+
+    let protos = values("x-forwarded-proto");
+    let addrs = values("x-forwarded-for");
+    // each proxy appends to both headers, so they are aligned from the nearest proxy
+    addrs.iter().enumerate().map(|(i, addr)| {
+        let proto = protos.len().checked_sub(addrs.len() - i).and_then(|i| protos.get(i));
+        (parse_node(addr), proto.map(|proto| proto.to_ascii_lowercase()))
+    }).collect()
+}
+
+/// Address in `Forwarded` or `X-Forwarded-For`, which may have a port and brackets.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    node.parse::<IpAddr>().ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| node.strip_prefix('[')?.strip_suffix(']')?.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use crate::forwarded::Proxies;
+
+    fn resolve(proxies: &Proxies, peer: &str, headers: &[(&str, &str)]) -> (String, String) {
+        let mut req = TestRequest::default().peer_addr(peer.parse().unwrap());
+        for header in headers {
+            req = req.append_header(*header);
+        }
+        let peer = proxies.resolve(req.to_http_request().head(), false);
+        (peer.ip.unwrap().to_string(), peer.scheme)
+    }
+
+    #[test]
+    fn test_resolve() {
+        let proxies = Proxies::new(vec!["10.0.0.0/8".parse().unwrap()]);
+        let https = |ip: &str| (ip.to_string(), "https".to_string());
+
+        let xff = [("X-Forwarded-For", "203.0.113.1, 10.0.0.2"), ("X-Forwarded-Proto", "https")];
+        assert_eq!(resolve(&proxies, "10.0.0.1:1234", &xff), https("203.0.113.1"));
+        // headers from untrusted clients are ignored
+        assert_eq!(resolve(&proxies, "198.51.100.1:1234", &xff), ("198.51.100.1".to_string(), "http".to_string()));
+        // spoofed addresses before an untrusted one are ignored
+        let spoofed = [("X-Forwarded-For", "192.0.2.1, 203.0.113.1")];
+        assert_eq!(resolve(&proxies, "10.0.0.1:1234", &spoofed).0, "203.0.113.1");
+
+        let forwarded = [("Forwarded", r#"for="[2001:db8::1]:4711";proto=https, for=10.0.0.2"#)];
+        assert_eq!(resolve(&proxies, "10.0.0.1:1234", &forwarded), https("2001:db8::1"));
+        let hidden = [("Forwarded", "for=_hidden;proto=https")];
+        assert_eq!(resolve(&proxies, "10.0.0.1:1234", &hidden).0, "10.0.0.1");
+    }
+}
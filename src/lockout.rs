@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use crate::config::LockoutConfig;
+
+/// Failed logins of a client address or a username.
+struct Failures {
+    count: u32,
+    /// Unix timestamp of the last failure
+    last: i64,
+    /// Unix timestamp until which logins are rejected
+    until: i64,
+}
+
+#[derive(Default)]
+struct State {
+    ips: HashMap<IpAddr, Failures>,
+    users: HashMap<String, Failures>,
+}
+
+/// Tracks failed logins by client address and by username, to slow down password guessing.
+///
+/// After `max_failures` failures, logins are rejected for `lockout` seconds, which is doubled on each
+/// further failure up to `max_lockout`. Failures are forgotten after a successful login, or after
+/// `max_lockout` seconds without another failure. Addresses in the allowlist are never tracked.
+pub struct Lockout {
+    config: LockoutConfig,
+    state: Mutex<State>,
+}
+
+impl Lockout {
+    pub fn new(config: LockoutConfig) -> Self {
+        Self { config, state: Mutex::new(State::default()) }
+    }
+
+    fn tracked(&self, ip: Option<IpAddr>) -> bool {
+        self.config.max_failures > 0 && !matches!(ip, Some(ip) if self.config.allowlist.iter().any(|net| net.contains(ip)))
+    }
+
+    /// Seconds until logins from `ip` or as `username` are allowed again, if either is locked out at `now`.
+    pub fn locked(&self, ip: Option<IpAddr>, username: Option<&str>, now: i64) -> Option<i64> {
+        if !self.tracked(ip) {
+            return None;
+        }
+        let state = self.state.lock().unwrap();
+        let ip = ip.and_then(|ip| state.ips.get(&ip));
+        let user = username.and_then(|username| state.users.get(username));
+        ip.into_iter().chain(user)
+            .map(|failures| failures.until - now)
+            .filter(|wait| *wait > 0)
+            .max()
+    }
+
+    /// Record a failed login from `ip` as `username` at `now`.
+    pub fn failed(&self, ip: Option<IpAddr>, username: Option<&str>, now: i64) {
+        if !self.tracked(ip) {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let max_lockout = self.config.max_lockout;
+        state.ips.retain(|_, failures| now - failures.last <= max_lockout);
+        state.users.retain(|_, failures| now - failures.last <= max_lockout);
+        if let Some(ip) = ip {
+            if let Some(lockout) = self.record(&mut state.ips, ip, now) {
+                log::warn!("Locked out client {} for {}s after too many failed logins", ip, lockout);
+            }
+        }
+        if let Some(username) = username {
+            if let Some(lockout) = self.record(&mut state.users, username.to_string(), now) {
+                log::warn!("Locked out user {} for {}s after too many failed logins", username, lockout);
+            }
+        }
+    }
+
+    /// Add a failure of `key`, returns seconds of lockout if it is locked out.
+    fn record<K: Eq + Hash>(&self, map: &mut HashMap<K, Failures>, key: K, now: i64) -> Option<i64> {
+        let failures = map.entry(key).or_insert(Failures { count: 0, last: now, until: 0 });
+        failures.count += 1;
+        failures.last = now;
+        let exceeded = failures.count.checked_sub(self.config.max_failures)?;
+        let lockout = self.config.lockout.saturating_mul(1 << exceeded.min(62)).min(self.config.max_lockout);
+        failures.until = now + lockout;
+        Some(lockout)
+    }
+
+    /// Forget failures of `ip` and `username` after a successful login.
+    pub fn succeeded(&self, ip: Option<IpAddr>, username: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(ip) = ip {
+            state.ips.remove(&ip);
+        }
+        state.users.remove(username);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use crate::config::{LockoutConfig, Network};
+    use crate::lockout::Lockout;
+
+    #[test]
+    fn test_network() {
+        let network: Network = "192.168.0.0/16".parse().unwrap();
+        assert!(network.contains("192.168.1.1".parse().unwrap()));
+        assert!(!network.contains("192.169.1.1".parse().unwrap()));
+        assert!(!network.contains("::1".parse().unwrap()));
+        assert!("0.0.0.0/0".parse::<Network>().unwrap().contains("1.2.3.4".parse().unwrap()));
+        assert!("::1".parse::<Network>().unwrap().contains("::1".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+    }
+
+    #[test]
+    fn test_lockout() {
+        let lockout = Lockout::new(LockoutConfig {
+            max_failures: 3,
+            lockout: 60,
+            max_lockout: 200,
+            allowlist: vec!["10.0.0.0/8".parse().unwrap()],
+        });
+        let ip: Option<IpAddr> = Some("192.168.1.1".parse().unwrap());
+        let other: Option<IpAddr> = Some("192.168.1.2".parse().unwrap());
+        for _ in 0..2 {
+            lockout.failed(ip, Some("alice"), 0);
+        }
+        assert_eq!(lockout.locked(ip, Some("alice"), 0), None);
+        lockout.failed(ip, Some("alice"), 0);
+        assert_eq!(lockout.locked(ip, None, 10), Some(50));
+        // the user is locked out from other clients too
+        assert_eq!(lockout.locked(other, Some("alice"), 10), Some(50));
+        assert_eq!(lockout.locked(other, Some("bob"), 10), None);
+        assert_eq!(lockout.locked(ip, Some("alice"), 60), None);
+
+        // doubled on each further failure, up to max_lockout
+        lockout.failed(ip, Some("alice"), 60);
+        assert_eq!(lockout.locked(ip, None, 60), Some(120));
+        lockout.failed(ip, Some("alice"), 180);
+        assert_eq!(lockout.locked(ip, None, 180), Some(200));
+
+        lockout.succeeded(ip, "alice");
+        assert_eq!(lockout.locked(ip, Some("alice"), 180), None);
+
+        // trusted networks are never locked out
+        let trusted: Option<IpAddr> = Some("10.1.2.3".parse().unwrap());
+        for _ in 0..5 {
+            lockout.failed(trusted, None, 0);
+        }
+        assert_eq!(lockout.locked(trusted, None, 0), None);
+    }
+}
@@ -1,127 +1,274 @@
 mod response;
+mod error;
 mod auth;
 mod models;
 mod config;
 mod repo;
+mod search;
+mod proxy;
+mod transcode;
+mod user;
+mod store;
+mod playlist;
+mod star;
+mod play;
+mod scrobble;
+mod now_playing;
+mod probe;
+mod availability;
+mod backend;
+mod scan;
+mod api_key;
+mod lockout;
+mod tls;
+mod forwarded;
+mod params;
+mod cover;
+#[cfg(test)]
+mod test_util;
 
-use actix_web::{HttpServer, Responder, HttpResponse, get, App, web, http};
+use actix_web::{HttpServer, Responder, HttpResponse, HttpRequest, get, route, App, web, http};
 use actix_web::middleware::{Logger, ErrorHandlers};
-use crate::auth::SonicAuth;
-use crate::config::{Config, AnnilConfig};
+use crate::auth::{SonicAuth, Client};
+use crate::error::Error;
+use crate::config::{Config, RepoConfig, TranscodeConfig, UserConfig, Role, PlainHttp};
 use crate::models::*;
-use actix_web::web::Query;
-use crate::repo::RepoManager;
+use crate::params::{Params, Query};
+use crate::repo::{RepoManager, Artist, Song};
+use crate::store::Store;
+use crate::playlist::PlaylistRecord;
+use crate::star::Stars;
+use crate::play::Plays;
+use crate::scrobble::{Listen, Scrobbler};
+use crate::probe::TrackInfos;
 use std::str::FromStr;
-use std::time::UNIX_EPOCH;
 use rand::Rng;
+use rand::seq::SliceRandom;
+use std::sync::{Arc, RwLock};
+use std::collections::HashSet;
 
-#[get("/ping.view")]
+#[route("/ping.view", method = "GET", method = "POST")]
 async fn ping() -> impl Responder {
-    HttpResponse::Ok()
-        .content_type("application/xml")
-        .body(response::ok(String::new()))
+    response::empty()
 }
 
-#[get("/getLicense.view")]
+#[route("/getLicense.view", method = "GET", method = "POST")]
 async fn get_license() -> impl Responder {
-    HttpResponse::Ok()
-        .content_type("application/xml")
-        .body(response::ok(String::from(r#"<license valid="true" email="mmf@mmf.moe" licenseExpires="2099-12-31T23:59:59"/>"#)))
+    response::ok(License {
+        valid: true,
+        email: "mmf@mmf.moe".to_owned(),
+        license_expires: "2099-12-31T23:59:59".to_owned(),
+    })
 }
 
-#[get("/getAlbumList.view")]
-async fn get_album_list(query: Query<SizeOffset>, data: web::Data<AppState>) -> impl Responder {
-    let mut albums = AlbumList::new();
-    let backend = &data.backend;
-    let repo = &data.repo;
-    for catalog in backend.albums().await.expect("Failed to get album list").iter().skip(query.offset) {
-        match repo.load_album(catalog) {
-            Some(album) => albums.push(Album::from_album(album, "@".to_string())),
-            None => {}
+/// Albums available in annil, listed in the way `getAlbumList` requested.
+///
+/// `rated` are catalogs rated by user, highest rated first, see [`Store::rated_albums`].
+fn album_list<'a>(query: &AlbumListQuery, repo: &'a RepoManager, available: &HashSet<String>, stars: &Stars, plays: &Plays, rated: &[String]) -> Vec<&'a anni_repo::Album> {
+    let mut albums: Vec<_> = match query.list_type {
+        AlbumListType::ByGenre => {
+            // categories are used as genres
+            let category = query.genre.as_deref().and_then(|genre| repo.load_category(genre));
+            match category {
+                Some(category) => category.info().albums()
+                    .chain(category.subcategories().flat_map(|sub| sub.albums()))
+                    .flat_map(|catalog| repo.load_albums(catalog))
+                    .collect(),
+                None => Vec::new(),
+            }
         }
-        if albums.inner.len() >= query.size {
-            break;
+        AlbumListType::Starred => stars.albums().filter_map(|catalog| repo.load_album(catalog)).collect(),
+        AlbumListType::Frequent => plays.frequent_albums().into_iter().filter_map(|catalog| repo.load_album(catalog)).collect(),
+        AlbumListType::Recent => plays.recent_albums().into_iter().filter_map(|catalog| repo.load_album(catalog)).collect(),
+        AlbumListType::Highest => rated.iter().filter_map(|catalog| repo.load_album(catalog)).collect(),
+        _ => repo.albums().collect(),
+    };
+    albums.retain(|album| available.contains(album.catalog()));
+
+    match query.list_type {
+        AlbumListType::Random => {
+            albums.shuffle(&mut rand::thread_rng());
+            albums.truncate(query.size());
+            return albums;
         }
+        AlbumListType::Newest => albums.sort_by_key(|album| std::cmp::Reverse(album.release_date().to_string())),
+        AlbumListType::AlphabeticalByName => albums.sort_by(|a, b| a.title().cmp(b.title())),
+        AlbumListType::AlphabeticalByArtist => albums.sort_by(|a, b| a.artist().cmp(b.artist())),
+        // already ordered by star time, play counts or ratings
+        AlbumListType::Starred | AlbumListType::Frequent | AlbumListType::Recent | AlbumListType::Highest => {}
+        AlbumListType::ByYear => {
+            let from = query.from_year.unwrap_or(0);
+            let to = query.to_year.unwrap_or(u32::MAX);
+            albums.retain(|album| {
+                let year = release_year(album).unwrap_or(0);
+                from.min(to) <= year && year <= from.max(to)
+            });
+            albums.sort_by_key(|album| album.release_date().to_string());
+            if from > to {
+                albums.reverse();
+            }
+        }
+        _ => albums.sort_by(|a, b| a.catalog().cmp(b.catalog())),
     }
-    HttpResponse::Ok()
-        .content_type("application/xml")
-        .body(response::ok(quick_xml::se::to_string(&albums).unwrap()))
+    albums.into_iter().skip(query.offset).take(query.size()).collect()
 }
 
-#[get("/stream.view")]
-async fn stream(query: Query<Id>, data: web::Data<AppState>) -> impl Responder {
-    let parts: Vec<_> = query.id.split("/").collect();
-    if parts.len() != 2 {
-        log::error!("Invalid stream id: {}", query.id);
-        HttpResponse::InternalServerError().finish()
-    } else {
-        HttpResponse::Found()
-            .append_header(("Location", data.backend.get_url(query.id.as_str())))
-            .finish()
+#[route("/getAlbumList.view", method = "GET", method = "POST")]
+async fn get_album_list(query: Query<AlbumListQuery>, user: web::ReqData<UserConfig>, data: web::Data<AppState>) -> error::Result<impl Responder> {
+    check_folder(&user, query.music_folder_id.as_deref())?;
+    let repo = data.repo();
+    let available = data.available_albums(&repo).await?;
+    let stars = data.store.stars(&user.username)?;
+    let plays = data.store.plays(&user.username)?;
+    let rated = data.store.rated_albums(&user.username)?;
+    let mut albums = AlbumList::new();
+    for album in album_list(&query, &repo, &available, &stars, &plays, &rated) {
+        albums.push(Album::from_album(album, "@".to_string()).with_stars(&stars));
+    }
+    Ok(response::ok(albums))
+}
+
+#[route("/getAlbumList2.view", method = "GET", method = "POST")]
+async fn get_album_list2(query: Query<AlbumListQuery>, user: web::ReqData<UserConfig>, data: web::Data<AppState>) -> error::Result<impl Responder> {
+    check_folder(&user, query.music_folder_id.as_deref())?;
+    let repo = data.repo();
+    let available = data.available_albums(&repo).await?;
+    let stars = data.store.stars(&user.username)?;
+    let plays = data.store.plays(&user.username)?;
+    let rated = data.store.rated_albums(&user.username)?;
+    Ok(response::ok(AlbumList2 {
+        album: album_list(&query, &repo, &available, &stars, &plays, &rated).into_iter()
+            .map(|album| album_id3(album, &data.store, &stars, &plays))
+            .collect(),
+    }))
+}
+
+#[route("/stream.view", method = "GET", method = "POST")]
+async fn stream(query: Query<StreamQuery>, user: web::ReqData<UserConfig>, client: web::ReqData<Client>, data: web::Data<AppState>, req: HttpRequest) -> error::Result<HttpResponse> {
+    let repo = data.repo();
+    if !user.has_role(Role::Stream) {
+        return Err(Error::NotAuthorized("User is not authorized to stream"));
     }
+    check_folder(&user, None)?;
+    let song = repo.load_track(&query.id).ok_or_else(|| Error::not_found("Song"))?;
+    data.now_playing.play(&user.username, &client.0, &song.id(), store::now());
+
+    let transcode = &data.transcode;
+    let format = match query.format.as_deref() {
+        Some("raw") => None,
+        Some(format) => Some(format),
+        None if query.max_bit_rate > 0 => Some(transcode.default_format.as_str()),
+        None => None,
+    };
+    Ok(match format.and_then(|format| transcode.profile(format)) {
+        Some(profile) => {
+            let bitrate = match query.max_bit_rate {
+                0 => profile.bitrate,
+                max => max.min(profile.bitrate),
+            };
+            let length = if query.estimate_content_length {
+                let infos = probe::probe(&data.backends, &data.store, std::slice::from_ref(&song), data.probe_concurrency).await;
+                infos.get(&song.id())
+                    .and_then(|info| info.duration)
+                    .map(|duration| transcode::estimate_length(duration, bitrate, query.time_offset))
+            } else {
+                None
+            };
+            transcode::transcode(transcode, &data.backends, &song.path(), profile, bitrate, query.time_offset, length).await?
+        }
+        None => proxy::serve(&data.backends, &song.path(), &req).await?,
+    })
 }
 
-#[get("/getCoverArt.view")]
-async fn get_cover_art(query: Query<Id>, data: web::Data<AppState>) -> impl Responder {
-    HttpResponse::Found()
-        .append_header(("Location", data.backend.get_url(&format!("{}/cover", query.id))))
-        .finish()
+#[route("/download.view", method = "GET", method = "POST")]
+async fn download(query: Query<Id>, user: web::ReqData<UserConfig>, data: web::Data<AppState>, req: HttpRequest) -> error::Result<HttpResponse> {
+    let repo = data.repo();
+    if !user.has_role(Role::Download) {
+        return Err(Error::NotAuthorized("User is not authorized to download"));
+    }
+    check_folder(&user, None)?;
+    let song = repo.load_track(&query.id).ok_or_else(|| Error::not_found("Song"))?;
+    proxy::serve(&data.backends, &song.path(), &req).await
+}
+
+#[route("/getCoverArt.view", method = "GET", method = "POST")]
+async fn get_cover_art(query: Query<CoverArtQuery>, user: web::ReqData<UserConfig>, data: web::Data<AppState>, req: HttpRequest) -> error::Result<HttpResponse> {
+    check_folder(&user, None)?;
+    let path = data.repo().cover_path(&query.id);
+    Ok(data.covers.serve(&data.backends, &path, query.size, &req).await)
 }
 
-#[get("/getMusicFolders.view")]
-async fn get_music_folders() -> impl Responder {
-    HttpResponse::Ok()
-        .content_type("application/xml")
-        .body(response::ok(r#"<musicFolders>
-<musicFolder id="@" name="Anni"/>
-</musicFolders>"#.to_owned()))
+/// All content is in the music folder `@`, so users not allowed to access it can access nothing.
+///
+/// `folder` is the `musicFolderId` parameter of endpoints accepting it.
+fn check_folder(user: &UserConfig, folder: Option<&str>) -> error::Result<()> {
+    if user.can_access_folder(folder.unwrap_or("@")) {
+        Ok(())
+    } else {
+        Err(Error::NotAuthorized("User is not authorized to access this music folder"))
+    }
+}
+
+#[route("/getMusicFolders.view", method = "GET", method = "POST")]
+async fn get_music_folders(user: web::ReqData<UserConfig>) -> impl Responder {
+    let mut music_folder = Vec::new();
+    if user.can_access_folder("@") {
+        music_folder.push(MusicFolder { id: "@".to_owned(), name: "Anni".to_owned() });
+    }
+    response::ok(MusicFolders { music_folder })
 }
 
 /// GetIndexes returns all categories
-#[get("/getIndexes.view")]
-async fn get_indexes(data: web::Data<AppState>) -> impl Responder {
-    let indexes = {
-        let mut indexes = Vec::new();
-        for (name, category) in data.repo.categories() {
-            indexes.push(IndexArtist { id: format!("/{}", name), name: category.info().name().to_string() });
-        }
-        let dir = Index {
+#[route("/getIndexes.view", method = "GET", method = "POST")]
+async fn get_indexes(query: Query<FolderQuery>, user: web::ReqData<UserConfig>, data: web::Data<AppState>) -> error::Result<impl Responder> {
+    check_folder(&user, query.music_folder_id.as_deref())?;
+    let repo = data.repo();
+    let stars = data.store.stars(&user.username)?;
+    let mut indexes = Vec::new();
+    for (name, category) in repo.categories() {
+        let id = format!("/{}", name);
+        indexes.push(IndexArtist { starred: stars.get(&id), id, name: category.info().name().to_string() });
+    }
+    Ok(response::ok(Indexes {
+        last_modified: store::now() as u64 * 1000,
+        ignored_articles: "The El La Los Las Le Les".to_owned(),
+        index: vec![Index {
             name: "Anni".to_owned(),
             inner: indexes,
-        };
-        quick_xml::se::to_string(&dir).unwrap()
-    };
-    HttpResponse::Ok()
-        .content_type("application/xml")
-        .body(response::ok(format!(r#"<indexes lastModified="{:?}" ignoredArticles="The El La Los Las Le Les">{}</indexes>"#,
-                                   std::time::SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos(), indexes)))
+        }],
+    }))
 }
 
 /// Music diretory id format
 /// `/{category_name}`: Get all sub categories
 /// `/{category_name}/`: Get all albums in category
 /// `/{category_name}/{subcategory_id}`: Get all albums in subcategory
+/// `ar-{md5 of name}`: Get all albums of artist
 /// `{catalog}`: Get all tracks in album
-#[get("getMusicDirectory.view")]
-async fn get_music_directory(query: Query<Id>, data: web::Data<AppState>) -> impl Responder {
-    let body = if query.id.starts_with("/") {
+#[route("/getMusicDirectory.view", method = "GET", method = "POST")]
+async fn get_music_directory(query: Query<Id>, user: web::ReqData<UserConfig>, data: web::Data<AppState>, req: HttpRequest) -> error::Result<HttpResponse> {
+    check_folder(&user, None)?;
+    let repo = data.repo();
+    let stars = data.store.stars(&user.username)?;
+    let plays = data.store.plays(&user.username)?;
+    if query.id.starts_with("/") {
         let category = &query.id[1..];
         let split: Vec<_> = category.split('/').collect();
-        let category = data.repo.load_category(split[0]);
+        let category = repo.load_category(split[0]).ok_or_else(|| Error::not_found("Category"))?;
         let subcategory = split.get(1);
 
         let mut albums = Vec::new();
-        let name = match (category, subcategory) {
+        let name = match subcategory {
             // category root, return [Default Category] and subcategories
-            (Some(category), None) => {
+            None => {
                 if category.subcategories().next().is_none() {
                     // does not have subcategory
-                    let albums_available = data.backend.albums().await.expect("Failed to get albums");
+                    let albums_available = data.available_albums(&repo).await?;
                     for catalog in category.info().albums() {
                         // return albums in default category directly
-                        for album in data.repo.load_albums(catalog) {
-                            if albums_available.iter().any(|x| x == album.catalog()) {
-                                albums.push(Album::from_album(album, query.id.to_string()));
+                        for album in repo.load_albums(catalog) {
+                            if albums_available.contains(album.catalog()) {
+                                albums.push(Album::from_album(album, query.id.to_string()).with_stars(&stars));
                             }
                         }
                     }
@@ -136,7 +283,8 @@ async fn get_music_directory(query: Query<Id>, data: web::Data<AppState>) -> imp
                             artist: "".to_string(),
                             is_dir: true,
                             cover_art: "".to_string(),
-                        });
+                            starred: None,
+                        }.with_stars(&stars));
                     }
 
                     for (i, subcategory) in category.subcategories().enumerate() {
@@ -147,164 +295,786 @@ async fn get_music_directory(query: Query<Id>, data: web::Data<AppState>) -> imp
                             artist: "".to_string(),
                             is_dir: true,
                             cover_art: "".to_string(),
-                        });
+                            starred: None,
+                        }.with_stars(&stars));
                     }
                 }
                 category.info().name().to_string()
             }
-            (Some(category), Some(subcategory)) => {
+            Some(subcategory) => {
                 let (name, catalogs): (_, Box<dyn Iterator<Item=&str>>) = if subcategory.is_empty() {
                     // root category
                     (category.info().name().to_string(), Box::new(category.info().albums()))
                 } else {
                     // sub category
-                    let subcategory = category.subcategories().nth(usize::from_str(subcategory).unwrap()).unwrap();
+                    let subcategory = usize::from_str(subcategory).ok()
+                        .and_then(|i| category.subcategories().nth(i))
+                        .ok_or_else(|| Error::not_found("Category"))?;
                     (subcategory.name().to_string(), Box::new(subcategory.albums()))
                 };
 
-                let albums_available = data.backend.albums().await.expect("Failed to load albums");
+                let albums_available = data.available_albums(&repo).await?;
                 for catalog in catalogs {
-                    for album in data.repo.load_albums(catalog) {
-                        if albums_available.iter().any(|x| x == album.catalog()) {
-                            albums.push(Album::from_album(album, query.id.to_string()));
+                    for album in repo.load_albums(catalog) {
+                        if albums_available.contains(album.catalog()) {
+                            albums.push(Album::from_album(album, query.id.to_string()).with_stars(&stars));
                         }
                     }
                 }
                 name
             }
-            (None, _) => {
-                // error, category does not exist
-                unreachable!()
-            }
         };
-        let dir = MusicDirectory {
+        Ok(response::ok(MusicDirectory {
             id: query.id.clone(),
             name,
             inner: albums,
-        };
-        quick_xml::se::to_string(&dir).unwrap()
+        }).respond_to(&req))
+    } else if let Some(artist) = repo.load_artist(&query.id) {
+        // artists returned by search2, with their albums
+        let albums_available = data.available_albums(&repo).await?;
+        let albums = artist.albums.iter()
+            .filter(|catalog| albums_available.contains(*catalog))
+            .filter_map(|catalog| repo.load_album(catalog))
+            .map(|album| Album::from_album(album, query.id.to_string()).with_stars(&stars))
+            .collect();
+        Ok(response::ok(MusicDirectory {
+            id: query.id.clone(),
+            name: artist.name.clone(),
+            inner: albums,
+        }).respond_to(&req))
     } else {
         // load tracks
-        let album = data.repo.load_album(&query.id).unwrap();
+        let album = repo.load_album(&query.id).ok_or_else(|| Error::not_found("Album"))?;
+        let songs: Vec<_> = repo::songs(album).collect();
+        let infos = probe::probe(&data.backends, &data.store, &songs, data.probe_concurrency).await;
         let mut tracks = Vec::new();
-        for (track_id, track) in album.discs()[0].tracks().iter().enumerate() {
-            let track_id = track_id + 1;
-            tracks.push(Track {
-                id: format!("{}/{}", query.id, track_id),
-                parent: query.id.clone(),
-                is_dir: false,
-
-                album: album.title().to_owned(),
-                title: track.title().to_owned(),
-                artist: track.artist().to_owned(),
-                track: track_id,
-                cover_art: query.id.clone(),
-                path: format!("[{}] {}/{}", query.id, album.title(), track_id), // FIXME: path
-                suffix: "flac".to_owned(), // FIXME: file format
-            });
+        for song in songs.iter() {
+            tracks.push(Track::from_song(song).with_stars(&stars).with_plays(&plays).with_info(&infos));
         }
-        let dir = AlbumDirectory {
+        Ok(response::ok(AlbumDirectory {
             id: query.id.clone(),
             name: album.title().to_owned(),
             inner: tracks,
-        };
-        quick_xml::se::to_string(&dir).unwrap()
-    };
-
-    HttpResponse::Ok()
-        .content_type("application/xml")
-        .body(response::ok(body))
+        }).respond_to(&req))
+    }
 }
 
-#[get("/getRandomSongs.view")]
-async fn get_random_songs(query: Query<RandomSongsQuery>, data: web::Data<AppState>) -> impl Responder {
+#[route("/getRandomSongs.view", method = "GET", method = "POST")]
+async fn get_random_songs(query: Query<RandomSongsQuery>, user: web::ReqData<UserConfig>, data: web::Data<AppState>) -> error::Result<impl Responder> {
+    check_folder(&user, query.music_folder_id.as_deref())?;
+    let repo = data.repo();
+    let stars = data.store.stars(&user.username)?;
+    let plays = data.store.plays(&user.username)?;
     let mut rng = rand::thread_rng();
     let mut songs = Vec::new();
     let mut tries = 0;
-    let albums = data.backend.albums().await.expect("Failed to get albums list");
-    while songs.len() < query.size && tries < 5 * query.size {
+    let available = data.available_albums(&repo).await?;
+    let albums: Vec<_> = available.iter().collect();
+    let size = query.size();
+    while songs.len() < size && tries < 5 * size {
         tries += 1;
-        let pos = rng.gen_range(0..albums.len());
-        match albums.iter().nth(pos) {
-            Some(catalog) => {
-                match data.repo.load_album(catalog) {
-                    Some(album) => {
-                        let tracks = album.discs()[0].tracks();
-                        let track_id = rng.gen_range(0..tracks.len());
-                        let ref track = tracks[track_id];
-                        let track_id = track_id + 1;
-                        use anni_repo::album::TrackType;
-                        match track.track_type() {
-                            TrackType::Normal | TrackType::Absolute => {
-                                songs.push(Track {
-                                    id: format!("{}/{}", catalog, track_id),
-                                    parent: catalog.to_string(),
-                                    is_dir: false,
-
-                                    album: album.title().to_owned(),
-                                    title: track.title().to_owned(),
-                                    artist: track.artist().to_owned(),
-                                    track: track_id,
-                                    cover_art: catalog.to_string(),
-                                    path: format!("[{}] {}/{}", catalog, album.title(), track_id),
-                                    suffix: "flac".to_owned(), // FIXME: file format
-                                });
-                            }
-                            _ => {}
-                        }
-                    }
-                    None => {}
+        // albums without tracks are skipped, as well as when there are no albums at all
+        let album = albums.choose(&mut rng).and_then(|catalog| repo.load_album(catalog));
+        let mut tracks: Vec<_> = album.map(|album| repo::songs(album).collect()).unwrap_or_default();
+        if tracks.is_empty() {
+            continue;
+        }
+        let song = tracks.swap_remove(rng.gen_range(0..tracks.len()));
+        use anni_repo::album::TrackType;
+        match song.track.track_type() {
+            TrackType::Normal | TrackType::Absolute => songs.push(song),
+            _ => {}
+        }
+    }
+    let infos = probe::probe(&data.backends, &data.store, &songs, data.probe_concurrency).await;
+    Ok(response::ok(RandomSongs {
+        inner: songs.iter().map(|song| Track::from_song(song).with_stars(&stars).with_plays(&plays).with_info(&infos)).collect(),
+    }))
+}
+
+struct SearchResult<'a> {
+    artists: Vec<&'a Artist>,
+    albums: Vec<&'a anni_repo::Album>,
+    songs: Vec<Song<'a>>,
+}
+
+/// Search artists, albums and songs, returns those available in annil only.
+fn search<'a>(query: &SearchQuery, repo: &'a RepoManager, available: &HashSet<String>) -> SearchResult<'a> {
+    let index = repo.index();
+    let artists = index.artists.search(query.query())
+        .into_iter()
+        .filter_map(|id| repo.load_artist(id))
+        .filter(|artist| artist.albums.iter().any(|catalog| available.contains(catalog)))
+        .skip(query.artist_offset)
+        .take(query.artist_count())
+        .collect();
+    let albums = index.albums.search(query.query())
+        .into_iter()
+        .filter(|catalog| available.contains(*catalog))
+        .skip(query.album_offset)
+        .take(query.album_count())
+        .filter_map(|catalog| repo.load_album(catalog))
+        .collect();
+    let songs = index.songs.search(query.query())
+        .into_iter()
+        .filter_map(|id| repo.load_track(id))
+        .filter(|song| available.contains(song.album.catalog()))
+        .skip(query.song_offset)
+        .take(query.song_count())
+        .collect();
+    SearchResult { artists, albums, songs }
+}
+
+#[route("/search2.view", method = "GET", method = "POST")]
+async fn search2(query: Query<SearchQuery>, user: web::ReqData<UserConfig>, data: web::Data<AppState>) -> error::Result<impl Responder> {
+    check_folder(&user, query.music_folder_id.as_deref())?;
+    let repo = data.repo();
+    let available = data.available_albums(&repo).await?;
+    let stars = data.store.stars(&user.username)?;
+    let plays = data.store.plays(&user.username)?;
+    let result = search(&query, &repo, &available);
+    let infos = probe::probe(&data.backends, &data.store, &result.songs, data.probe_concurrency).await;
+    Ok(response::ok(SearchResult2 {
+        artist: result.artists.into_iter()
+            .map(|artist| IndexArtist { id: artist.id.clone(), name: artist.name.clone(), starred: stars.get(&artist.id) })
+            .collect(),
+        album: result.albums.into_iter().map(|album| Album::from_album(album, "@".to_string()).with_stars(&stars)).collect(),
+        song: result.songs.iter().map(|song| Track::from_song(song).with_stars(&stars).with_plays(&plays).with_info(&infos)).collect(),
+    }))
+}
+
+#[route("/search3.view", method = "GET", method = "POST")]
+async fn search3(query: Query<SearchQuery>, user: web::ReqData<UserConfig>, data: web::Data<AppState>) -> error::Result<impl Responder> {
+    check_folder(&user, query.music_folder_id.as_deref())?;
+    let repo = data.repo();
+    let available = data.available_albums(&repo).await?;
+    let stars = data.store.stars(&user.username)?;
+    let plays = data.store.plays(&user.username)?;
+    let result = search(&query, &repo, &available);
+    let infos = probe::probe(&data.backends, &data.store, &result.songs, data.probe_concurrency).await;
+    Ok(response::ok(SearchResult3 {
+        artist: result.artists.into_iter().map(|artist| artist_id3(artist, &available).with_stars(&stars)).collect(),
+        album: result.albums.into_iter().map(|album| album_id3(album, &data.store, &stars, &plays)).collect(),
+        song: result.songs.iter().map(|song| Child::from_song(song).with_stars(&stars).with_plays(&plays).with_info(&infos)).collect(),
+    }))
+}
+
+/// Convert album to id3 format, with duration summed from track info cached in store.
+///
+/// Songs are not probed here, as it would be too slow for album lists.
+fn album_id3(album: &anni_repo::Album, store: &Store, stars: &Stars, plays: &Plays) -> AlbumID3 {
+    let songs: Vec<_> = repo::songs(album).collect();
+    let infos = probe::cached(store, &songs);
+    AlbumID3::from_album(album).with_stars(stars).with_plays(plays).with_duration(&songs, &infos)
+}
+
+/// Convert artist to id3 format, counting albums available in annil only.
+fn artist_id3(artist: &Artist, available: &HashSet<String>) -> ArtistID3 {
+    ArtistID3 {
+        id: artist.id.clone(),
+        name: artist.name.clone(),
+        album_count: artist.albums.iter().filter(|catalog| available.contains(*catalog)).count(),
+        starred: None,
+        album: Vec::new(),
+    }
+}
+
+#[route("/getArtists.view", method = "GET", method = "POST")]
+async fn get_artists(query: Query<FolderQuery>, user: web::ReqData<UserConfig>, data: web::Data<AppState>) -> error::Result<impl Responder> {
+    check_folder(&user, query.music_folder_id.as_deref())?;
+    let repo = data.repo();
+    let available = data.available_albums(&repo).await?;
+    let stars = data.store.stars(&user.username)?;
+    let mut artists: Vec<_> = repo.artists()
+        .map(|artist| artist_id3(artist, &available).with_stars(&stars))
+        .filter(|artist| artist.album_count > 0)
+        .collect();
+    artists.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut index: Vec<IndexID3> = Vec::new();
+    for artist in artists {
+        let name = match artist.name.chars().next() {
+            Some(c) if c.is_ascii_alphabetic() => c.to_ascii_uppercase().to_string(),
+            _ => "#".to_string(),
+        };
+        match index.iter_mut().find(|i| i.name == name) {
+            Some(i) => i.artist.push(artist),
+            None => index.push(IndexID3 { name, artist: vec![artist] }),
+        }
+    }
+    index.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(response::ok(ArtistsID3 {
+        ignored_articles: "".to_string(),
+        index,
+    }))
+}
+
+#[route("/getArtist.view", method = "GET", method = "POST")]
+async fn get_artist(query: Query<Id>, user: web::ReqData<UserConfig>, data: web::Data<AppState>) -> error::Result<impl Responder> {
+    check_folder(&user, None)?;
+    let repo = data.repo();
+    let artist = repo.load_artist(&query.id).ok_or_else(|| Error::not_found("Artist"))?;
+    let available = data.available_albums(&repo).await?;
+    let stars = data.store.stars(&user.username)?;
+    let plays = data.store.plays(&user.username)?;
+    let mut result = artist_id3(artist, &available).with_stars(&stars);
+    result.album = artist.albums.iter()
+        .filter(|catalog| available.contains(*catalog))
+        .filter_map(|catalog| repo.load_album(catalog))
+        .map(|album| album_id3(album, &data.store, &stars, &plays))
+        .collect();
+    Ok(response::ok(result))
+}
+
+#[route("/getAlbum.view", method = "GET", method = "POST")]
+async fn get_album(query: Query<Id>, user: web::ReqData<UserConfig>, data: web::Data<AppState>) -> error::Result<impl Responder> {
+    check_folder(&user, None)?;
+    let repo = data.repo();
+    let album = repo.load_album(&query.id).ok_or_else(|| Error::not_found("Album"))?;
+    let stars = data.store.stars(&user.username)?;
+    let plays = data.store.plays(&user.username)?;
+    let songs: Vec<_> = repo::songs(album).collect();
+    let infos = probe::probe(&data.backends, &data.store, &songs, data.probe_concurrency).await;
+    let mut result = AlbumID3::from_album(album).with_stars(&stars).with_plays(&plays).with_duration(&songs, &infos);
+    for song in songs.iter() {
+        result.song.push(Child::from_song(song).with_stars(&stars).with_plays(&plays).with_info(&infos));
+    }
+    Ok(response::ok(result))
+}
+
+#[route("/getSong.view", method = "GET", method = "POST")]
+async fn get_song(query: Query<Id>, user: web::ReqData<UserConfig>, data: web::Data<AppState>) -> error::Result<impl Responder> {
+    check_folder(&user, None)?;
+    let repo = data.repo();
+    let stars = data.store.stars(&user.username)?;
+    let plays = data.store.plays(&user.username)?;
+    let song = repo.load_track(&query.id).ok_or_else(|| Error::not_found("Song"))?;
+    let infos = probe::probe(&data.backends, &data.store, std::slice::from_ref(&song), data.probe_concurrency).await;
+    Ok(response::ok(Child::from_song(&song).with_stars(&stars).with_plays(&plays).with_info(&infos)))
+}
+
+#[route("/getUser.view", method = "GET", method = "POST")]
+async fn get_user(query: Query<UsernameQuery>, user: web::ReqData<UserConfig>, data: web::Data<AppState>) -> error::Result<impl Responder> {
+    if query.username != user.username && !user.has_role(Role::Admin) {
+        return Err(Error::NotAuthorized("User is not authorized to get details for other users"));
+    }
+    let user = data.users.get(&query.username).ok_or_else(|| Error::not_found("User"))?;
+    Ok(response::ok(User::from_config(user)))
+}
+
+#[route("/getUsers.view", method = "GET", method = "POST")]
+async fn get_users(user: web::ReqData<UserConfig>, data: web::Data<AppState>) -> error::Result<impl Responder> {
+    if !user.has_role(Role::Admin) {
+        return Err(Error::NotAuthorized("User is not authorized to get user list"));
+    }
+    let mut users: Vec<_> = data.users.iter().map(User::from_config).collect();
+    users.sort_by(|a, b| a.username.cmp(&b.username));
+    Ok(response::ok(Users { user: users }))
+}
+
+#[route("/getOpenSubsonicExtensions.view", method = "GET", method = "POST")]
+async fn get_open_subsonic_extensions() -> impl Responder {
+    response::ok(vec![
+        OpenSubsonicExtension::new("apiKeyAuthentication", &[1]),
+        OpenSubsonicExtension::new("formPost", &[1]),
+    ])
+}
+
+#[route("/tokenInfo.view", method = "GET", method = "POST")]
+async fn token_info(user: web::ReqData<UserConfig>) -> impl Responder {
+    response::ok(TokenInfo { username: user.username.clone() })
+}
+
+/// Create an API key for current user, the key is only returned here.
+#[route("/createApiKey.view", method = "GET", method = "POST")]
+async fn create_api_key(query: Query<ApiKeyQuery>, user: web::ReqData<UserConfig>, data: web::Data<AppState>) -> error::Result<impl Responder> {
+    let (id, key) = data.store.create_api_key(&user.username, &query.name)?;
+    Ok(response::ok(ApiKey {
+        id: id.to_string(),
+        name: query.name.clone(),
+        key: Some(key),
+        created: iso8601(store::now()),
+        last_used: None,
+    }))
+}
+
+#[route("/getApiKeys.view", method = "GET", method = "POST")]
+async fn get_api_keys(user: web::ReqData<UserConfig>, data: web::Data<AppState>) -> error::Result<impl Responder> {
+    let keys = data.store.api_keys(&user.username)?;
+    Ok(response::ok(ApiKeys { api_key: keys.iter().map(ApiKey::from_record).collect() }))
+}
+
+#[route("/deleteApiKey.view", method = "GET", method = "POST")]
+async fn delete_api_key(query: Query<ApiKeyId>, user: web::ReqData<UserConfig>, data: web::Data<AppState>) -> error::Result<impl Responder> {
+    if !data.store.delete_api_key(&user.username, query.id)? {
+        return Err(Error::not_found("API key"));
+    }
+    Ok(response::empty())
+}
+
+/// Songs in playlist which are still available in metadata repository and annil.
+fn playlist_songs<'a>(playlist: &'a PlaylistRecord, repo: &RepoManager, available: &HashSet<String>) -> Vec<(usize, &'a str)> {
+    playlist.songs.iter()
+        .enumerate()
+        .filter(|(_, song)| match repo.load_track(song) {
+            Some(song) => available.contains(song.album.catalog()),
+            None => false,
+        })
+        .map(|(i, song)| (i, song.as_str()))
+        .collect()
+}
+
+/// Songs in playlist to show, see [playlist_songs].
+fn playlist_entries<'a>(playlist: &PlaylistRecord, repo: &'a RepoManager, available: &HashSet<String>) -> Vec<Song<'a>> {
+    playlist_songs(playlist, repo, available)
+        .into_iter()
+        .filter_map(|(_, song)| repo.load_track(song))
+        .collect()
+}
+
+fn to_playlist(playlist: &PlaylistRecord, songs: &[Song], stars: &Stars, plays: &Plays, infos: &TrackInfos, with_entries: bool) -> Playlist {
+    let entry: Vec<_> = songs.iter()
+        .map(|song| Child::from_song(song).with_stars(stars).with_plays(plays).with_info(infos))
+        .collect();
+    Playlist {
+        id: playlist.id.to_string(),
+        name: playlist.name.clone(),
+        comment: playlist.comment.clone(),
+        owner: playlist.owner.clone(),
+        public: playlist.public,
+        song_count: entry.len(),
+        duration: entry.iter().filter_map(|song| song.duration).map(u64::from).sum(),
+        created: iso8601(playlist.created),
+        changed: iso8601(playlist.changed),
+        cover_art: entry.first().map(|song| song.cover_art.clone()),
+        entry: if with_entries { entry } else { Vec::new() },
+    }
+}
+
+#[route("/getPlaylists.view", method = "GET", method = "POST")]
+async fn get_playlists(query: Query<PlaylistsQuery>, user: web::ReqData<UserConfig>, data: web::Data<AppState>) -> error::Result<impl Responder> {
+    check_folder(&user, None)?;
+    let repo = data.repo();
+    let playlists = match &query.username {
+        Some(username) if username != &user.username => {
+            if !user.has_role(Role::Admin) {
+                return Err(Error::NotAuthorized("User is not authorized to get playlists of other users"));
+            }
+            data.store.playlists(username, false)
+        }
+        _ => data.store.playlists(&user.username, true),
+    }?;
+    let available = data.available_albums(&repo).await?;
+    let stars = data.store.stars(&user.username)?;
+    let plays = data.store.plays(&user.username)?;
+    Ok(response::ok(Playlists {
+        playlist: playlists.iter().map(|p| {
+            let songs = playlist_entries(p, &repo, &available);
+            // probing songs of all playlists would be too slow, use cached duration only
+            let infos = probe::cached(&data.store, &songs);
+            to_playlist(p, &songs, &stars, &plays, &infos, false)
+        }).collect(),
+    }))
+}
+
+/// Load playlist visible to user.
+fn load_playlist(id: i64, user: &UserConfig, store: &Store) -> error::Result<PlaylistRecord> {
+    store.playlist(id)?
+        .filter(|p| p.public || p.owner == user.username || user.has_role(Role::Admin))
+        .ok_or_else(|| Error::not_found("Playlist"))
+}
+
+#[route("/getPlaylist.view", method = "GET", method = "POST")]
+async fn get_playlist(query: Query<PlaylistId>, user: web::ReqData<UserConfig>, data: web::Data<AppState>) -> error::Result<impl Responder> {
+    check_folder(&user, None)?;
+    let repo = data.repo();
+    let playlist = load_playlist(query.id, &user, &data.store)?;
+    let available = data.available_albums(&repo).await?;
+    let stars = data.store.stars(&user.username)?;
+    let plays = data.store.plays(&user.username)?;
+    let songs = playlist_entries(&playlist, &repo, &available);
+    let infos = probe::probe(&data.backends, &data.store, &songs, data.probe_concurrency).await;
+    Ok(response::ok(to_playlist(&playlist, &songs, &stars, &plays, &infos, true)))
+}
+
+/// Create a playlist, or replace songs of an existing one.
+#[route("/createPlaylist.view", method = "GET", method = "POST")]
+async fn create_playlist(query: Query<CreatePlaylistQuery>, user: web::ReqData<UserConfig>, data: web::Data<AppState>, params: Params) -> error::Result<impl Responder> {
+    let repo = data.repo();
+    if !user.has_role(Role::Playlist) {
+        return Err(Error::NotAuthorized("User is not authorized to create playlists"));
+    }
+    let songs = params.all("songId");
+    let id = match (query.playlist_id, &query.name) {
+        (Some(id), _) => {
+            let mut playlist = load_playlist(id, &user, &data.store)?;
+            if playlist.owner != user.username {
+                return Err(Error::NotAuthorized("User is not the owner of playlist"));
+            }
+            if let Some(name) = &query.name {
+                playlist.name = name.clone();
+            }
+            playlist.songs = songs;
+            data.store.update_playlist(&playlist)?;
+            id
+        }
+        (None, Some(name)) => data.store.create_playlist(&user.username, name, &songs)?,
+        (None, None) => return Err(Error::MissingParameter("name")),
+    };
+
+    let playlist = data.store.playlist(id)?.ok_or_else(|| Error::not_found("Playlist"))?;
+    let available = data.available_albums(&repo).await?;
+    let stars = data.store.stars(&user.username)?;
+    let plays = data.store.plays(&user.username)?;
+    let songs = playlist_entries(&playlist, &repo, &available);
+    let infos = probe::probe(&data.backends, &data.store, &songs, data.probe_concurrency).await;
+    Ok(response::ok(to_playlist(&playlist, &songs, &stars, &plays, &infos, true)))
+}
+
+#[route("/updatePlaylist.view", method = "GET", method = "POST")]
+async fn update_playlist(query: Query<UpdatePlaylistQuery>, user: web::ReqData<UserConfig>, data: web::Data<AppState>, params: Params) -> error::Result<impl Responder> {
+    let repo = data.repo();
+    let mut playlist = load_playlist(query.playlist_id, &user, &data.store)?;
+    if playlist.owner != user.username && !user.has_role(Role::Admin) {
+        return Err(Error::NotAuthorized("User is not the owner of playlist"));
+    }
+    if let Some(name) = &query.name {
+        playlist.name = name.clone();
+    }
+    if let Some(comment) = &query.comment {
+        playlist.comment = comment.clone();
+    }
+    if let Some(public) = query.public {
+        playlist.public = public;
+    }
+
+    // indexes are based on the songs client sees, which does not include unavailable ones
+    let available = data.available_albums(&repo).await?;
+    let visible = playlist_songs(&playlist, &repo, &available);
+    let removed: Vec<usize> = params.all("songIndexToRemove")
+        .iter()
+        .filter_map(|index| usize::from_str(index).ok())
+        .filter_map(|index| visible.get(index).map(|(i, _)| *i))
+        .collect();
+    playlist.songs = playlist.songs.into_iter()
+        .enumerate()
+        .filter(|(i, _)| !removed.contains(i))
+        .map(|(_, song)| song)
+        .chain(params.all("songIdToAdd"))
+        .collect();
+
+    data.store.update_playlist(&playlist)?;
+    Ok(response::empty())
+}
+
+#[route("/deletePlaylist.view", method = "GET", method = "POST")]
+async fn delete_playlist(query: Query<PlaylistId>, user: web::ReqData<UserConfig>, data: web::Data<AppState>) -> error::Result<impl Responder> {
+    let playlist = load_playlist(query.id, &user, &data.store)?;
+    if playlist.owner != user.username && !user.has_role(Role::Admin) {
+        return Err(Error::NotAuthorized("User is not the owner of playlist"));
+    }
+    data.store.delete_playlist(playlist.id)?;
+    Ok(response::empty())
+}
+
+/// Ids to star or unstar, from `id`, `albumId` and `artistId`.
+fn star_ids(params: &Params) -> Vec<String> {
+    let mut ids = params.all("id");
+    ids.extend(params.all("albumId"));
+    ids.extend(params.all("artistId"));
+    ids
+}
+
+#[route("/star.view", method = "GET", method = "POST")]
+async fn star_items(user: web::ReqData<UserConfig>, data: web::Data<AppState>, params: Params) -> error::Result<impl Responder> {
+    data.store.star(&user.username, &star_ids(&params))?;
+    Ok(response::empty())
+}
+
+#[route("/unstar.view", method = "GET", method = "POST")]
+async fn unstar_items(user: web::ReqData<UserConfig>, data: web::Data<AppState>, params: Params) -> error::Result<impl Responder> {
+    data.store.unstar(&user.username, &star_ids(&params))?;
+    Ok(response::empty())
+}
+
+/// Starred songs available in annil.
+fn starred_songs<'a>(stars: &Stars, repo: &'a RepoManager, available: &HashSet<String>) -> Vec<Song<'a>> {
+    stars.songs()
+        .filter_map(|id| repo.load_track(id))
+        .filter(|song| available.contains(song.album.catalog()))
+        .collect()
+}
+
+#[route("/getStarred.view", method = "GET", method = "POST")]
+async fn get_starred(query: Query<FolderQuery>, user: web::ReqData<UserConfig>, data: web::Data<AppState>) -> error::Result<impl Responder> {
+    check_folder(&user, query.music_folder_id.as_deref())?;
+    let repo = data.repo();
+    let available = data.available_albums(&repo).await?;
+    let stars = data.store.stars(&user.username)?;
+    let plays = data.store.plays(&user.username)?;
+    let songs = starred_songs(&stars, &repo, &available);
+    let infos = probe::probe(&data.backends, &data.store, &songs, data.probe_concurrency).await;
+    Ok(response::ok(Starred {
+        artist: stars.artists()
+            .filter_map(|id| repo.load_artist(id))
+            .map(|artist| IndexArtist { id: artist.id.clone(), name: artist.name.clone(), starred: stars.get(&artist.id) })
+            .collect(),
+        album: stars.albums()
+            .filter(|catalog| available.contains(*catalog))
+            .filter_map(|catalog| repo.load_album(catalog))
+            .map(|album| Album::from_album(album, "@".to_string()).with_stars(&stars))
+            .collect(),
+        song: songs.iter()
+            .map(|song| Track::from_song(song).with_stars(&stars).with_plays(&plays).with_info(&infos))
+            .collect(),
+    }))
+}
+
+#[route("/getStarred2.view", method = "GET", method = "POST")]
+async fn get_starred2(query: Query<FolderQuery>, user: web::ReqData<UserConfig>, data: web::Data<AppState>) -> error::Result<impl Responder> {
+    check_folder(&user, query.music_folder_id.as_deref())?;
+    let repo = data.repo();
+    let available = data.available_albums(&repo).await?;
+    let stars = data.store.stars(&user.username)?;
+    let plays = data.store.plays(&user.username)?;
+    let songs = starred_songs(&stars, &repo, &available);
+    let infos = probe::probe(&data.backends, &data.store, &songs, data.probe_concurrency).await;
+    Ok(response::ok(Starred2 {
+        artist: stars.artists()
+            .filter_map(|id| repo.load_artist(id))
+            .map(|artist| artist_id3(artist, &available).with_stars(&stars))
+            .collect(),
+        album: stars.albums()
+            .filter(|catalog| available.contains(*catalog))
+            .filter_map(|catalog| repo.load_album(catalog))
+            .map(|album| album_id3(album, &data.store, &stars, &plays))
+            .collect(),
+        song: songs.iter()
+            .map(|song| Child::from_song(song).with_stars(&stars).with_plays(&plays).with_info(&infos))
+            .collect(),
+    }))
+}
+
+#[route("/scrobble.view", method = "GET", method = "POST")]
+async fn submit_scrobble(query: Query<ScrobbleQuery>, user: web::ReqData<UserConfig>, client: web::ReqData<Client>, data: web::Data<AppState>, params: Params) -> error::Result<impl Responder> {
+    let repo = data.repo();
+    let ids = params.all("id");
+    if ids.is_empty() {
+        return Err(Error::MissingParameter("id"));
+    }
+    // time of each play in milliseconds
+    let times = params.all("time");
+    let mut listens = Vec::new();
+    for (i, id) in ids.into_iter().enumerate() {
+        let song = repo.load_track(&id).ok_or_else(|| Error::NotFound(format!("Song {} not found", id)))?;
+        let time = times.get(i)
+            .and_then(|time| time.parse::<i64>().ok())
+            .map(|time| time / 1000)
+            // plays in the future are not possible
+            .filter(|time| (0..=store::now()).contains(time))
+            .unwrap_or_else(store::now);
+        listens.push((song.id(), Listen::new(song.album, song.track, time)));
+    }
+
+    let user = user.into_inner();
+    for (id, listen) in listens.iter() {
+        if query.submission {
+            data.store.record_play(&user.username, id, listen.time)?;
+        } else {
+            data.now_playing.play(&user.username, &client.0, id, listen.time);
+        }
+    }
+    // do not keep clients waiting for scrobbling services
+    let data = data.clone();
+    actix_web::rt::spawn(async move {
+        for (_, listen) in listens {
+            if query.submission {
+                if let Err(e) = data.scrobbler.scrobble(&data.store, &user, &listen).await {
+                    log::error!("Failed to queue scrobble: {}", e);
                 }
+            } else {
+                data.scrobbler.now_playing(&user, &listen).await;
             }
-            None => {}
         }
+    });
+    Ok(response::empty())
+}
+
+#[route("/getNowPlaying.view", method = "GET", method = "POST")]
+async fn get_now_playing(user: web::ReqData<UserConfig>, data: web::Data<AppState>) -> error::Result<impl Responder> {
+    check_folder(&user, None)?;
+    let repo = data.repo();
+    let stars = data.store.stars(&user.username)?;
+    let plays = data.store.plays(&user.username)?;
+    let now = store::now();
+    let (playing, songs): (Vec<_>, Vec<_>) = data.now_playing.entries(now).into_iter()
+        .filter_map(|playing| repo.load_track(&playing.song_id).map(|song| (playing, song)))
+        .unzip();
+    let infos = probe::probe(&data.backends, &data.store, &songs, data.probe_concurrency).await;
+    Ok(response::ok(NowPlaying {
+        entry: playing.iter().zip(songs.iter())
+            .map(|(playing, song)| {
+                Child::from_song(song)
+                    .with_stars(&stars)
+                    .with_plays(&plays)
+                    .with_info(&infos)
+                    .with_playing(playing, now)
+            })
+            .collect(),
+    }))
+}
+
+/// Server metrics in Prometheus text format, for admins only.
+#[get("/metrics")]
+async fn metrics(user: web::ReqData<UserConfig>, data: web::Data<AppState>) -> error::Result<HttpResponse> {
+    if !user.has_role(Role::Admin) {
+        return Err(Error::NotAuthorized("User is not authorized to view metrics"));
     }
-    let songs = RandomSongs { inner: songs };
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(data.backends.metrics()))
+}
 
-    HttpResponse::Ok()
-        .content_type("application/xml")
-        .body(response::ok(quick_xml::se::to_string(&songs).unwrap()))
+/// Health of annil backends, for admins only.
+#[get("/status")]
+async fn backend_status(user: web::ReqData<UserConfig>, data: web::Data<AppState>) -> error::Result<HttpResponse> {
+    if !user.has_role(Role::Admin) {
+        return Err(Error::NotAuthorized("User is not authorized to view status"));
+    }
+    Ok(HttpResponse::Ok().json(data.backends.status()))
 }
 
-#[get("/getUser.view")]
-async fn get_user() -> impl Responder {
-    HttpResponse::Ok()
-        .content_type("application/xml")
-        .body(response::ok(format!(r#"<user username="{}" scrobblingEnabled="false" adminRole="false" settingsRole="false" downloadRole="false" uploadRole="false" playlistRole="false" coverArtRole="true" commentRole="false" podcastRole="false" streamRole="true" jukeboxRole="false" shareRole="false">
-<folder>@</folder>
-</user>"#, std::env::var("ANNI_USER").unwrap())))
+#[route("/startScan.view", method = "GET", method = "POST")]
+async fn start_scan(user: web::ReqData<UserConfig>, data: web::Data<AppState>) -> error::Result<impl Responder> {
+    if !user.has_role(Role::Admin) {
+        return Err(Error::NotAuthorized("User is not authorized to scan metadata repository"));
+    }
+    if data.scanner.start() {
+        let state = data.clone();
+        actix_web::rt::spawn(async move { state.rescan().await });
+    }
+    Ok(response::ok(data.scanner.status()))
 }
 
-#[get("/getPlaylists.view")]
-async fn get_playlists() -> impl Responder {
-    HttpResponse::Ok()
-        .content_type("application/xml")
-        .body(response::ok("<playlists></playlists>".to_owned()))
+#[route("/getScanStatus.view", method = "GET", method = "POST")]
+async fn get_scan_status(data: web::Data<AppState>) -> impl Responder {
+    response::ok(data.scanner.status())
+}
+
+impl AppState {
+    /// Snapshot of metadata repository.
+    ///
+    /// Handlers should get it once per request, so that a rescan finished in the middle does not mix up results.
+    fn repo(&self) -> Arc<RepoManager> {
+        self.repo.read().unwrap().clone()
+    }
+
+    /// Catalogs of albums available in annil, resolved in `repo`.
+    ///
+    /// Album lists of annil backends are cached, see [`availability::Availability`].
+    async fn available_albums(&self, repo: &RepoManager) -> anyhow::Result<Arc<HashSet<String>>> {
+        self.backends.albums(repo.generation(), |catalogs| repo.available(catalogs)).await
+    }
+
+    /// Refresh catalogs of albums available in annil now.
+    async fn refresh_albums(&self) -> anyhow::Result<Arc<HashSet<String>>> {
+        let repo = self.repo();
+        self.backends.refresh(repo.generation(), |catalogs| repo.available(catalogs)).await
+    }
+
+    /// Rewrite ids in store which are changed in `repo`, so that stars and plays of them are not lost.
+    fn migrate_ids(&self, repo: &RepoManager) {
+        match self.store.migrate_ids(|id| repo.current_id(id)) {
+            Ok(0) => {}
+            Ok(migrated) => log::info!("Migrated {} ids in local store", migrated),
+            Err(e) => log::error!("Failed to migrate ids in local store: {}", e),
+        }
+    }
+
+    /// Reload metadata repository and swap it in, after [`scan::Scanner::start`] succeeded.
+    async fn rescan(&self) {
+        log::info!("Start rescanning metadata repository...");
+        let now = std::time::Instant::now();
+        match self.scanner.scan(self.repo_config.root.clone(), self.repo_config.split_discs).await {
+            Ok(repo) => {
+                self.migrate_ids(&repo);
+                // available albums resolved in the previous repository are dropped by its generation
+                *self.repo.write().unwrap() = Arc::new(repo);
+                log::info!("Metadata repository rescanned, used {:?}", now.elapsed());
+            }
+            Err(e) => log::error!("{:#}, keeping the previous one", e),
+        }
+    }
 }
 
 struct AppState {
-    repo: RepoManager,
-    backend: AnnilConfig,
+    repo: RwLock<Arc<RepoManager>>,
+    repo_config: RepoConfig,
+    scanner: scan::Scanner,
+    users: Arc<user::Users>,
+    store: Arc<Store>,
+    scrobbler: Scrobbler,
+    now_playing: now_playing::Tracker,
+    backends: backend::Backends,
+    transcode: TranscodeConfig,
+    covers: cover::Covers,
+    /// Songs probed in annil at once, see [`probe::probe`]
+    probe_concurrency: usize,
 }
 
 async fn init_state(config: &Config) -> anyhow::Result<web::Data<AppState>> {
-    std::env::set_var("ANNI_USER", &config.server.username);
-    std::env::set_var("ANNI_PASSWD", &config.server.password);
-    std::env::set_var("ANNI_PASSWD_HEX", hex::encode(&config.server.password));
+    let users = user::Users::new(config);
+    if users.is_empty() {
+        anyhow::bail!("No user configured");
+    }
+    if config.backends().is_empty() {
+        anyhow::bail!("No annil server configured");
+    }
 
-    log::info!("Start validating annil server...");
-    let albums = config.annil.albums().await?;
-    log::info!("Annil server validated, found {} albums", albums.len());
+    log::info!("Opening local store at {}...", config.store.path);
+    let store = Arc::new(Store::open(&config.store.path)?);
 
     log::info!("Start initializing metadata repository...");
     let now = std::time::SystemTime::now();
-    let repo = RepoManager::new(&config.repo.root);
+    let repo = RepoManager::new(&config.repo.root, config.repo.split_discs)?;
     log::info!("Metadata repository initialization finished, used {:?}", now.elapsed().unwrap());
 
-    Ok(web::Data::new(AppState {
-        repo,
-        backend: config.annil.clone(),
-    }))
+    let state = web::Data::new(AppState {
+        scanner: scan::Scanner::new(repo.albums().count()),
+        repo: RwLock::new(Arc::new(repo)),
+        repo_config: config.repo.clone(),
+        users: Arc::new(users),
+        store,
+        scrobbler: Scrobbler::default(),
+        now_playing: now_playing::Tracker::new(config.server.now_playing_timeout as i64 * 60),
+        backends: backend::Backends::new(config.backends()),
+        transcode: config.transcode.clone(),
+        covers: cover::Covers::new(config.cover.clone())?,
+        probe_concurrency: config.server.probe_concurrency.max(1),
+    });
+    state.migrate_ids(&state.repo());
+
+    log::info!("Start validating annil servers...");
+    let albums = state.refresh_albums().await?;
+    for backend in state.backends.status() {
+        match backend.last_error {
+            None => log::info!("Annil server {} validated, found {} albums", backend.name, backend.albums.albums),
+            Some(e) => log::warn!("Annil server {} is unavailable: {}", backend.name, e),
+        }
+    }
+    log::info!("Found {} albums in annil servers", albums.len());
+    Ok(state)
+}
+
+/// Access log with client address and scheme resolved through trusted proxies.
+fn logger(proxies: forwarded::Proxies) -> Logger {
+    let scheme_proxies = proxies.clone();
+    Logger::new(r#"%{client}xi %{scheme}xi "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T"#)
+        .custom_request_replace("client", move |req| match proxies.resolve(req.head(), req.app_config().secure()).ip {
+            Some(ip) => ip.to_string(),
+            None => "-".to_string(),
+        })
+        .custom_request_replace("scheme", move |req| scheme_proxies.resolve(req.head(), req.app_config().secure()).scheme)
 }
 
 #[actix_web::main]
@@ -312,30 +1082,147 @@ async fn main() -> anyhow::Result<()> {
     env_logger::init();
     let config = Config::from_file(std::env::args().nth(1).unwrap_or("config.toml".to_owned()))?;
     let state = init_state(&config).await?;
-    HttpServer::new(move || {
+
+    let scrobble_state = state.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(scrobble::FLUSH_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = scrobble_state.scrobbler.flush(&scrobble_state.store, &scrobble_state.users).await {
+                log::error!("Failed to retry queued scrobbles: {}", e);
+            }
+        }
+    });
+
+    let token_auth = config.server.token_auth;
+    let lockout = Arc::new(lockout::Lockout::new(config.server.lockout.clone()));
+    let proxies = forwarded::Proxies::new(config.server.trusted_proxies.clone());
+    let base_path = config.server.base_path();
+    let app = move || {
         App::new()
             .app_data(state.clone())
-            .wrap(SonicAuth)
+            .wrap(SonicAuth::new(state.users.clone(), state.store.clone(), lockout.clone(), proxies.clone(), token_auth))
+            .wrap(params::ReadParams)
             .wrap(ErrorHandlers::new()
                 .handler(http::StatusCode::NOT_FOUND, response::gone)
+                .handler(http::StatusCode::INTERNAL_SERVER_ERROR, error::render)
             )
-            .wrap(Logger::default())
-            .service(web::scope("/rest")
-                .service(ping)
-                .service(get_license)
-                .service(get_user)
-                .service(get_album_list)
-                .service(get_music_folders)
-                .service(get_indexes)
-                .service(get_music_directory)
-                .service(get_random_songs)
-                .service(get_cover_art)
-                .service(get_playlists) // needed by SoundWaves
-                .service(stream)
+            .wrap(logger(proxies.clone()))
+            .service(web::scope(&base_path)
+                .service(web::scope("/rest")
+                    .service(ping)
+                    .service(get_license)
+                    .service(get_user)
+                    .service(get_users)
+                    .service(get_open_subsonic_extensions)
+                    .service(token_info)
+                    .service(create_api_key)
+                    .service(get_api_keys)
+                    .service(delete_api_key)
+                    .service(get_album_list)
+                    .service(get_album_list2)
+                    .service(get_music_folders)
+                    .service(get_indexes)
+                    .service(get_music_directory)
+                    .service(get_random_songs)
+                    .service(search2)
+                    .service(search3)
+                    .service(get_artists)
+                    .service(get_artist)
+                    .service(get_album)
+                    .service(get_song)
+                    .service(get_cover_art)
+                    .service(get_playlists)
+                    .service(get_playlist)
+                    .service(create_playlist)
+                    .service(update_playlist)
+                    .service(delete_playlist)
+                    .service(star_items)
+                    .service(unstar_items)
+                    .service(get_starred)
+                    .service(get_starred2)
+                    .service(submit_scrobble)
+                    .service(get_now_playing)
+                    .service(start_scan)
+                    .service(get_scan_status)
+                    .service(stream)
+                    .service(download)
+                )
+                .service(metrics)
+                .service(backend_status)
             )
-    })
-        .bind(config.server.listen("0.0.0.0:1710"))?
-        .run()
-        .await?;
+    };
+
+    let listen = config.server.listen("0.0.0.0:1710");
+    let mut servers = Vec::new();
+    match &config.server.tls {
+        None => servers.push(HttpServer::new(app).bind(listen)?.run()),
+        Some(tls) => {
+            let resolver = Arc::new(tls::CertResolver::new(tls)?);
+            #[cfg(unix)]
+            actix_web::rt::spawn(tls::reload_on_hangup(resolver.clone()));
+            let server = HttpServer::new(app).bind_rustls(&tls.listen, resolver.server_config())?;
+            match tls.http {
+                PlainHttp::Serve => servers.push(server.bind(listen)?.run()),
+                PlainHttp::Redirect => {
+                    servers.push(server.run());
+                    let redirect = web::Data::new(tls::Redirect::new(tls)?);
+                    servers.push(HttpServer::new(move || {
+                        App::new()
+                            .app_data(redirect.clone())
+                            .wrap(Logger::default())
+                            .default_service(web::to(tls::redirect))
+                    }).bind(listen)?.run());
+                }
+                PlainHttp::Disabled => servers.push(server.run()),
+            }
+        }
+    }
+    futures_util::future::try_join_all(servers).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use actix_web::{web, App};
+    use actix_web::test::{init_service, call_service, read_body, TestRequest};
+    use crate::auth::SonicAuth;
+    use crate::config::LockoutConfig;
+    use crate::{check_folder, forwarded, lockout, params, test_util, get_music_folders};
+    use crate::store::Store;
+    use crate::user::Users;
+
+    #[test]
+    fn test_post_form() {
+        let users = Arc::new(Users::new(&test_util::config("")));
+        let store = Arc::new(Store::memory().unwrap());
+        let lockout = Arc::new(lockout::Lockout::new(LockoutConfig::default()));
+        actix_web::rt::System::new().block_on(async {
+            let app = init_service(App::new()
+                .wrap(SonicAuth::new(users, store, lockout, forwarded::Proxies::new(vec![]), true))
+                .wrap(params::ReadParams)
+                .service(web::scope("/rest").service(get_music_folders))
+            ).await;
+
+            let req = TestRequest::post().uri("/rest/getMusicFolders.view")
+                .insert_header(("Content-Type", "application/x-www-form-urlencoded"))
+                .set_payload("u=alice&p=sesame&v=1.16.1&c=test&f=json")
+                .to_request();
+            let body = read_body(call_service(&app, req).await).await;
+            let body = std::str::from_utf8(&body).unwrap();
+            assert!(body.contains(r#""status":"ok""#), "{}", body);
+            assert!(body.contains(r#""musicFolder":[{"id":"@""#), "{}", body);
+        });
+    }
+
+    #[test]
+    fn test_check_folder() {
+        let config = test_util::config("[[users]]\nusername = \"bob\"\npassword = \"sesame\"\nfolders = []");
+        let (alice, bob) = (&config.users[0], &config.users[1]);
+        assert!(check_folder(alice, None).is_ok());
+        assert!(check_folder(alice, Some("@")).is_ok());
+        assert_eq!(check_folder(alice, Some("other")).unwrap_err().code(), 50);
+        assert_eq!(check_folder(bob, None).unwrap_err().code(), 50);
+    }
+}
@@ -1,4 +1,13 @@
 use serde::{Serialize, Deserialize};
+use crate::response::Body;
+use crate::repo::{artist_id, songs, Song};
+use crate::config::{UserConfig, Role};
+use chrono::{TimeZone, Utc, SecondsFormat};
+use crate::star::Stars;
+use crate::play::Plays;
+use crate::now_playing::Playing;
+use crate::probe::TrackInfos;
+use crate::api_key::ApiKeyRecord;
 
 #[derive(Deserialize)]
 pub struct Id {
@@ -6,11 +15,67 @@ pub struct Id {
 }
 
 #[derive(Deserialize)]
-pub struct SizeOffset {
+#[serde(rename_all = "camelCase")]
+pub struct StreamQuery {
+    pub id: String,
+    /// Maximum bitrate in kbps, 0 for no limit
+    #[serde(default)]
+    pub max_bit_rate: u32,
+    pub format: Option<String>,
+    pub time_offset: Option<u32>,
+    /// Whether to set `Content-Length` of transcoded stream, estimated from duration and bitrate
+    #[serde(default)]
+    pub estimate_content_length: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderQuery {
+    pub music_folder_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CoverArtQuery {
+    pub id: String,
+    /// Maximum width and height, original cover is returned if absent
+    pub size: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AlbumListType {
+    Random,
+    Newest,
+    Highest,
+    Frequent,
+    Recent,
+    AlphabeticalByName,
+    AlphabeticalByArtist,
+    Starred,
+    ByYear,
+    ByGenre,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AlbumListQuery {
+    #[serde(rename = "type")]
+    pub list_type: AlbumListType,
     #[serde(default = "ten")]
-    pub size: usize,
+    size: usize,
     #[serde(default)]
     pub offset: usize,
+    pub from_year: Option<u32>,
+    pub to_year: Option<u32>,
+    pub genre: Option<String>,
+    pub music_folder_id: Option<String>,
+}
+
+impl AlbumListQuery {
+    /// List size, at most 500.
+    pub fn size(&self) -> usize {
+        self.size.min(500)
+    }
 }
 
 fn ten() -> usize {
@@ -26,6 +91,8 @@ pub struct Album {
     pub artist: String,
     pub is_dir: bool,
     pub cover_art: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub starred: Option<String>,
 }
 
 impl Album {
@@ -37,12 +104,18 @@ impl Album {
             artist,
             is_dir: true,
             cover_art: catalog,
+            starred: None,
         }
     }
 
     pub fn from_album(album: &anni_repo::Album, parent: String) -> Self {
         Self::new(album.catalog().to_owned(), album.title().to_owned(), album.artist().to_owned(), parent)
     }
+
+    pub fn with_stars(mut self, stars: &Stars) -> Self {
+        self.starred = stars.get(&self.id);
+        self
+    }
 }
 
 #[derive(Serialize)]
@@ -52,6 +125,10 @@ pub struct AlbumList {
     pub inner: Vec<Album>,
 }
 
+impl Body for AlbumList {
+    const NAME: &'static str = "albumList";
+}
+
 impl AlbumList {
     pub fn new() -> Self {
         Self {
@@ -64,6 +141,16 @@ impl AlbumList {
     }
 }
 
+#[derive(Serialize)]
+#[serde(rename = "albumList2")]
+pub struct AlbumList2 {
+    pub album: Vec<AlbumID3>,
+}
+
+impl Body for AlbumList2 {
+    const NAME: &'static str = "albumList2";
+}
+
 #[derive(Serialize)]
 #[serde(rename = "directory")]
 pub struct AlbumDirectory {
@@ -73,6 +160,10 @@ pub struct AlbumDirectory {
     pub inner: Vec<Track>,
 }
 
+impl Body for AlbumDirectory {
+    const NAME: &'static str = "directory";
+}
+
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Track {
@@ -84,9 +175,75 @@ pub struct Track {
     pub title: String,
     pub artist: String,
     pub track: usize,
+    pub disc_number: usize,
     pub cover_art: String,
     pub path: String,
     pub suffix: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bit_rate: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub starred: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub play_count: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub played: Option<String>,
+}
+
+impl Track {
+    pub fn from_song(song: &Song) -> Self {
+        let id = song.id();
+        let catalog = song.album.catalog();
+        Self {
+            path: format!("[{}] {}/{}", catalog, song.album.title(), &id[catalog.len() + 1..]), // FIXME: path
+            id,
+            parent: catalog.to_string(),
+            is_dir: false,
+
+            album: song.album.title().to_owned(),
+            title: song.track.title().to_owned(),
+            artist: song.track.artist().to_owned(),
+            track: song.track_id,
+            disc_number: song.disc_id,
+            cover_art: catalog.to_string(),
+            // probed later, see `with_info`
+            suffix: "flac".to_owned(),
+            size: None,
+            content_type: None,
+            duration: None,
+            bit_rate: None,
+            starred: None,
+            play_count: None,
+            played: None,
+        }
+    }
+
+    pub fn with_stars(mut self, stars: &Stars) -> Self {
+        self.starred = stars.get(&self.id);
+        self
+    }
+
+    pub fn with_plays(mut self, plays: &Plays) -> Self {
+        self.play_count = plays.count(&self.id);
+        self.played = plays.played(&self.id);
+        self
+    }
+
+    pub fn with_info(mut self, infos: &TrackInfos) -> Self {
+        if let Some(info) = infos.get(&self.id) {
+            self.suffix = info.suffix().to_owned();
+            self.size = Some(info.size);
+            self.content_type = Some(info.content_type.clone());
+            self.duration = info.duration;
+            self.bit_rate = info.bit_rate;
+        }
+        self
+    }
 }
 
 #[derive(Serialize)]
@@ -98,6 +255,22 @@ pub struct MusicDirectory {
     pub inner: Vec<Album>,
 }
 
+impl Body for MusicDirectory {
+    const NAME: &'static str = "directory";
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase", rename = "indexes")]
+pub struct Indexes {
+    pub last_modified: u64,
+    pub ignored_articles: String,
+    pub index: Vec<Index>,
+}
+
+impl Body for Indexes {
+    const NAME: &'static str = "indexes";
+}
+
 #[derive(Serialize)]
 #[serde(rename = "index")]
 pub struct Index {
@@ -110,6 +283,8 @@ pub struct Index {
 pub struct IndexArtist {
     pub id: String,
     pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub starred: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -119,17 +294,644 @@ pub struct RandomSongs {
     pub inner: Vec<Track>,
 }
 
+impl Body for RandomSongs {
+    const NAME: &'static str = "randomSongs";
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct RandomSongsQuery {
     #[serde(default = "ten")]
-    pub size: usize,
+    size: usize,
     pub music_folder_id: Option<String>,
 }
 
+impl RandomSongsQuery {
+    /// Number of songs, at most 500.
+    pub fn size(&self) -> usize {
+        self.size.min(500)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchQuery {
+    #[serde(default)]
+    pub query: String,
+    #[serde(default = "twenty")]
+    artist_count: usize,
+    #[serde(default)]
+    pub artist_offset: usize,
+    #[serde(default = "twenty")]
+    album_count: usize,
+    #[serde(default)]
+    pub album_offset: usize,
+    #[serde(default = "twenty")]
+    song_count: usize,
+    #[serde(default)]
+    pub song_offset: usize,
+    pub music_folder_id: Option<String>,
+}
+
+impl SearchQuery {
+    /// Some clients send `""` to list everything.
+    pub fn query(&self) -> &str {
+        self.query.trim_matches('"')
+    }
+
+    /// Number of artists, at most 500.
+    pub fn artist_count(&self) -> usize {
+        self.artist_count.min(500)
+    }
+
+    /// Number of albums, at most 500.
+    pub fn album_count(&self) -> usize {
+        self.album_count.min(500)
+    }
+
+    /// Number of songs, at most 500.
+    pub fn song_count(&self) -> usize {
+        self.song_count.min(500)
+    }
+}
+
+fn twenty() -> usize {
+    20
+}
+
+#[derive(Serialize)]
+#[serde(rename = "searchResult2")]
+pub struct SearchResult2 {
+    pub artist: Vec<IndexArtist>,
+    pub album: Vec<Album>,
+    pub song: Vec<Track>,
+}
+
+impl Body for SearchResult2 {
+    const NAME: &'static str = "searchResult2";
+}
+
+#[derive(Serialize)]
+#[serde(rename = "searchResult3")]
+pub struct SearchResult3 {
+    pub artist: Vec<ArtistID3>,
+    pub album: Vec<AlbumID3>,
+    pub song: Vec<Child>,
+}
+
+impl Body for SearchResult3 {
+    const NAME: &'static str = "searchResult3";
+}
+
+/// Year of album release date.
+pub fn release_year(album: &anni_repo::Album) -> Option<u32> {
+    album.release_date().to_string().split('-').next()?.parse().ok()
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase", rename = "artists")]
+pub struct ArtistsID3 {
+    pub ignored_articles: String,
+    pub index: Vec<IndexID3>,
+}
+
+impl Body for ArtistsID3 {
+    const NAME: &'static str = "artists";
+}
+
+#[derive(Serialize)]
+pub struct IndexID3 {
+    pub name: String,
+    pub artist: Vec<ArtistID3>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase", rename = "artist")]
+pub struct ArtistID3 {
+    pub id: String,
+    pub name: String,
+    pub album_count: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub starred: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub album: Vec<AlbumID3>,
+}
+
+impl ArtistID3 {
+    pub fn with_stars(mut self, stars: &Stars) -> Self {
+        self.starred = stars.get(&self.id);
+        self
+    }
+}
+
+impl Body for ArtistID3 {
+    const NAME: &'static str = "artist";
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase", rename = "album")]
+pub struct AlbumID3 {
+    pub id: String,
+    pub name: String,
+    pub artist: String,
+    pub artist_id: String,
+    pub cover_art: String,
+    pub song_count: usize,
+    pub duration: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub year: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub starred: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub play_count: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub played: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub song: Vec<Child>,
+}
+
+impl AlbumID3 {
+    pub fn from_album(album: &anni_repo::Album) -> Self {
+        Self {
+            id: album.catalog().to_owned(),
+            name: album.title().to_owned(),
+            artist: album.artist().to_owned(),
+            artist_id: artist_id(album.artist()),
+            cover_art: album.catalog().to_owned(),
+            song_count: songs(album).count(),
+            duration: 0,
+            year: release_year(album),
+            starred: None,
+            play_count: None,
+            played: None,
+            song: Vec::new(),
+        }
+    }
+
+    pub fn with_stars(mut self, stars: &Stars) -> Self {
+        self.starred = stars.get(&self.id);
+        self
+    }
+
+    pub fn with_plays(mut self, plays: &Plays) -> Self {
+        self.play_count = plays.count(&self.id);
+        self.played = plays.played(&self.id);
+        self
+    }
+
+    /// Sum durations of `songs` of the album, skipping those not in `infos`.
+    pub fn with_duration(mut self, songs: &[Song], infos: &TrackInfos) -> Self {
+        self.duration = songs.iter()
+            .filter_map(|song| infos.get(&song.id())?.duration)
+            .map(u64::from)
+            .sum();
+        self
+    }
+}
+
+impl Body for AlbumID3 {
+    const NAME: &'static str = "album";
+}
+
+/// A song entry, used by id3 endpoints.
+///
+/// Fields of `NowPlayingEntry` are included here, as quick-xml serializes flattened fields as elements.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase", rename = "song")]
+pub struct Child {
+    pub id: String,
+    pub parent: String,
+    pub is_dir: bool,
+    pub title: String,
+    pub album: String,
+    pub artist: String,
+    pub track: usize,
+    pub disc_number: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub year: Option<u32>,
+    pub cover_art: String,
+    pub suffix: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bit_rate: Option<u32>,
+    pub path: String,
+    pub album_id: String,
+    pub artist_id: String,
+    #[serde(rename = "type")]
+    pub media_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub starred: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub play_count: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub played: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub minutes_ago: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub player_id: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub player_name: Option<String>,
+}
+
+impl Child {
+    pub fn from_song(song: &Song) -> Self {
+        let id = song.id();
+        let catalog = song.album.catalog();
+        Self {
+            path: format!("[{}] {}/{}", catalog, song.album.title(), &id[catalog.len() + 1..]),
+            id,
+            parent: catalog.to_string(),
+            is_dir: false,
+            title: song.track.title().to_owned(),
+            album: song.album.title().to_owned(),
+            artist: song.track.artist().to_owned(),
+            track: song.track_id,
+            disc_number: song.disc_id,
+            year: release_year(song.album),
+            cover_art: catalog.to_string(),
+            // probed later, see `with_info`
+            suffix: "flac".to_owned(),
+            size: None,
+            content_type: None,
+            duration: None,
+            bit_rate: None,
+            album_id: catalog.to_string(),
+            artist_id: artist_id(song.track.artist()),
+            media_type: "music".to_owned(),
+            starred: None,
+            play_count: None,
+            played: None,
+            username: None,
+            minutes_ago: None,
+            player_id: None,
+            player_name: None,
+        }
+    }
+
+    pub fn with_stars(mut self, stars: &Stars) -> Self {
+        self.starred = stars.get(&self.id);
+        self
+    }
+
+    pub fn with_plays(mut self, plays: &Plays) -> Self {
+        self.play_count = plays.count(&self.id);
+        self.played = plays.played(&self.id);
+        self
+    }
+
+    pub fn with_info(mut self, infos: &TrackInfos) -> Self {
+        if let Some(info) = infos.get(&self.id) {
+            self.suffix = info.suffix().to_owned();
+            self.size = Some(info.size);
+            self.content_type = Some(info.content_type.clone());
+            self.duration = info.duration;
+            self.bit_rate = info.bit_rate;
+        }
+        self
+    }
+
+    pub fn with_playing(mut self, playing: &Playing, now: i64) -> Self {
+        self.username = Some(playing.username.clone());
+        self.minutes_ago = Some((now - playing.started) / 60);
+        self.player_id = Some(playing.player_id);
+        self.player_name = Some(playing.client.clone());
+        self
+    }
+}
+
+impl Body for Child {
+    const NAME: &'static str = "song";
+}
+
+#[derive(Serialize)]
+#[serde(rename = "nowPlaying")]
+pub struct NowPlaying {
+    pub entry: Vec<Child>,
+}
+
+impl Body for NowPlaying {
+    const NAME: &'static str = "nowPlaying";
+}
+
+#[derive(Serialize)]
+#[serde(rename = "starred")]
+pub struct Starred {
+    pub artist: Vec<IndexArtist>,
+    pub album: Vec<Album>,
+    pub song: Vec<Track>,
+}
+
+impl Body for Starred {
+    const NAME: &'static str = "starred";
+}
+
+#[derive(Serialize)]
+#[serde(rename = "starred2")]
+pub struct Starred2 {
+    pub artist: Vec<ArtistID3>,
+    pub album: Vec<AlbumID3>,
+    pub song: Vec<Child>,
+}
+
+impl Body for Starred2 {
+    const NAME: &'static str = "starred2";
+}
+
+#[derive(Serialize)]
+#[serde(rename = "scanStatus")]
+pub struct ScanStatus {
+    pub scanning: bool,
+    pub count: usize,
+}
+
+impl Body for ScanStatus {
+    const NAME: &'static str = "scanStatus";
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase", rename = "license")]
+pub struct License {
+    pub valid: bool,
+    pub email: String,
+    pub license_expires: String,
+}
+
+impl Body for License {
+    const NAME: &'static str = "license";
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase", rename = "musicFolders")]
+pub struct MusicFolders {
+    pub music_folder: Vec<MusicFolder>,
+}
+
+impl Body for MusicFolders {
+    const NAME: &'static str = "musicFolders";
+}
+
+#[derive(Serialize)]
+pub struct MusicFolder {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase", rename = "user")]
+pub struct User {
+    pub username: String,
+    pub scrobbling_enabled: bool,
+    pub admin_role: bool,
+    pub settings_role: bool,
+    pub download_role: bool,
+    pub upload_role: bool,
+    pub playlist_role: bool,
+    pub cover_art_role: bool,
+    pub comment_role: bool,
+    pub podcast_role: bool,
+    pub stream_role: bool,
+    pub jukebox_role: bool,
+    pub share_role: bool,
+    pub folder: Vec<Folder>,
+}
+
+impl User {
+    pub fn from_config(user: &UserConfig) -> Self {
+        Self {
+            username: user.username.clone(),
+            // plays are always recorded locally, forwarding is optional
+            scrobbling_enabled: true,
+            admin_role: user.has_role(Role::Admin),
+            settings_role: user.has_role(Role::Admin),
+            download_role: user.has_role(Role::Download),
+            upload_role: false,
+            playlist_role: user.has_role(Role::Playlist),
+            cover_art_role: true,
+            comment_role: false,
+            podcast_role: false,
+            stream_role: user.has_role(Role::Stream),
+            jukebox_role: false,
+            share_role: false,
+            folder: user.folders.iter().map(|f| Folder::new(f.clone())).collect(),
+        }
+    }
+}
+
+impl Body for User {
+    const NAME: &'static str = "user";
+}
+
+#[derive(Serialize)]
+#[serde(rename = "users")]
+pub struct Users {
+    pub user: Vec<User>,
+}
+
+impl Body for Users {
+    const NAME: &'static str = "users";
+}
+
+#[derive(Deserialize)]
+pub struct UsernameQuery {
+    pub username: String,
+}
+
+#[derive(Serialize)]
+pub struct Folder {
+    #[serde(rename = "$value")]
+    pub id: String,
+}
+
+impl Folder {
+    pub fn new(id: String) -> Self {
+        Self { id }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename = "playlists")]
+pub struct Playlists {
+    pub playlist: Vec<Playlist>,
+}
+
+impl Body for Playlists {
+    const NAME: &'static str = "playlists";
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase", rename = "playlist")]
+pub struct Playlist {
+    pub id: String,
+    pub name: String,
+    pub comment: String,
+    pub owner: String,
+    pub public: bool,
+    pub song_count: usize,
+    pub duration: u64,
+    pub created: String,
+    pub changed: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cover_art: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub entry: Vec<Child>,
+}
+
+impl Body for Playlist {
+    const NAME: &'static str = "playlist";
+}
+
+#[derive(Deserialize)]
+pub struct PlaylistsQuery {
+    pub username: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreatePlaylistQuery {
+    pub playlist_id: Option<i64>,
+    pub name: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdatePlaylistQuery {
+    pub playlist_id: i64,
+    pub name: Option<String>,
+    pub comment: Option<String>,
+    pub public: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct PlaylistId {
+    pub id: i64,
+}
+
+#[derive(Deserialize)]
+pub struct ScrobbleQuery {
+    /// Whether this is a finished play, or a "now playing" notification
+    #[serde(default = "truth")]
+    pub submission: bool,
+}
+
+fn truth() -> bool {
+    true
+}
+
+#[derive(Serialize)]
+#[serde(rename = "tokenInfo")]
+pub struct TokenInfo {
+    pub username: String,
+}
+
+impl Body for TokenInfo {
+    const NAME: &'static str = "tokenInfo";
+}
+
+/// An OpenSubsonic extension supported, listed directly in `openSubsonicExtensions` array.
+#[derive(Serialize)]
+#[serde(rename = "openSubsonicExtensions")]
+pub struct OpenSubsonicExtension {
+    pub name: &'static str,
+    pub versions: Vec<ExtensionVersion>,
+}
+
+impl OpenSubsonicExtension {
+    pub fn new(name: &'static str, versions: &[u32]) -> Self {
+        Self { name, versions: versions.iter().map(|&version| ExtensionVersion { version }).collect() }
+    }
+}
+
+impl Body for Vec<OpenSubsonicExtension> {
+    const NAME: &'static str = "openSubsonicExtensions";
+}
+
+#[derive(Serialize)]
+pub struct ExtensionVersion {
+    #[serde(rename = "$value")]
+    pub version: u32,
+}
+
+#[derive(Serialize)]
+#[serde(rename = "apiKeys")]
+pub struct ApiKeys {
+    #[serde(rename = "apiKey")]
+    pub api_key: Vec<ApiKey>,
+}
+
+impl Body for ApiKeys {
+    const NAME: &'static str = "apiKeys";
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase", rename = "apiKey")]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    /// Only returned when created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    pub created: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_used: Option<String>,
+}
+
+impl ApiKey {
+    pub fn from_record(record: &ApiKeyRecord) -> Self {
+        Self {
+            id: record.id.to_string(),
+            name: record.name.clone(),
+            key: None,
+            created: iso8601(record.created),
+            last_used: record.last_used.map(iso8601),
+        }
+    }
+}
+
+impl Body for ApiKey {
+    const NAME: &'static str = "apiKey";
+}
+
+#[derive(Deserialize)]
+pub struct ApiKeyQuery {
+    #[serde(default)]
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+pub struct ApiKeyId {
+    pub id: i64,
+}
+
+/// Format unix timestamp in ISO 8601, out of range timestamps are formatted as the epoch.
+pub fn iso8601(timestamp: i64) -> String {
+    Utc.timestamp_opt(timestamp, 0).single()
+        .unwrap_or_else(|| Utc.timestamp_opt(0, 0).unwrap())
+        .to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+#[derive(Serialize)]
+#[serde(rename = "error")]
+pub struct SonicError {
+    pub code: u32,
+    pub message: String,
+}
+
+impl Body for SonicError {
+    const NAME: &'static str = "error";
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::models::{Album, AlbumList};
+    use actix_web::web::Query;
+    use crate::models::{Album, AlbumList, IndexArtist, SearchQuery, SearchResult2};
 
     #[test]
     fn test_album() {
@@ -137,8 +939,9 @@ mod tests {
             "TEST-001".to_string(),
             "TEST-001".to_string(),
             "Artist".to_string(),
+            "@".to_string(),
         )).unwrap();
-        assert_eq!(result, r#"<album id="TEST-001" title="TEST-001" artist="Artist" isDir="true" coverArt="TEST-001"/>"#);
+        assert_eq!(result, r#"<album id="TEST-001" parent="@" title="TEST-001" artist="Artist" isDir="true" coverArt="TEST-001"/>"#);
     }
 
     #[test]
@@ -149,14 +952,34 @@ mod tests {
                     "TEST-001".to_string(),
                     "TEST-001".to_string(),
                     "Artist".to_string(),
+                    "@".to_string(),
                 ),
                 Album::new(
                     "TEST-002".to_string(),
                     "TEST-002".to_string(),
                     "Artist".to_string(),
+                    "@".to_string(),
                 ),
             ]
         }).unwrap();
-        assert_eq!(result, r#"<albumList><album id="TEST-001" title="TEST-001" artist="Artist" isDir="true" coverArt="TEST-001"/><album id="TEST-002" title="TEST-002" artist="Artist" isDir="true" coverArt="TEST-002"/></albumList>"#);
+        assert_eq!(result, r#"<albumList><album id="TEST-001" parent="@" title="TEST-001" artist="Artist" isDir="true" coverArt="TEST-001"/><album id="TEST-002" parent="@" title="TEST-002" artist="Artist" isDir="true" coverArt="TEST-002"/></albumList>"#);
+    }
+
+    #[test]
+    fn test_search_result2() {
+        let result = quick_xml::se::to_string(&SearchResult2 {
+            artist: vec![IndexArtist { id: "ar-1".to_string(), name: "Artist".to_string(), starred: None }],
+            album: Vec::new(),
+            song: Vec::new(),
+        }).unwrap();
+        assert_eq!(result, r#"<searchResult2><artist id="ar-1" name="Artist"/></searchResult2>"#);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_search_query() {
+        let query = Query::<SearchQuery>::from_query("query=&songCount=100000&albumCount=5").unwrap();
+        assert_eq!(query.song_count(), 500);
+        assert_eq!(query.album_count(), 5);
+        assert_eq!(query.artist_count(), 20);
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// A song being played by a client of user.
+#[derive(Clone)]
+pub struct Playing {
+    pub username: String,
+    /// Client name from `c` parameter
+    pub client: String,
+    pub player_id: u32,
+    pub song_id: String,
+    /// Unix timestamp when the song started playing
+    pub started: i64,
+    /// Unix timestamp of the last stream request or notification of the song
+    last_seen: i64,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<(String, String), Playing>,
+    /// Player ids are kept stable for each user and client
+    players: HashMap<(String, String), u32>,
+}
+
+/// Songs being played, one entry for each user and client.
+///
+/// Entries are kept in memory only, and expire after not being seen for a while.
+pub struct Tracker {
+    /// Timeout in seconds
+    timeout: i64,
+    state: Mutex<State>,
+}
+
+impl Tracker {
+    pub fn new(timeout: i64) -> Self {
+        Self { timeout, state: Mutex::new(State::default()) }
+    }
+
+    /// Record that client of user is playing song at `now`.
+    pub fn play(&self, username: &str, client: &str, song_id: &str, now: i64) {
+        let mut state = self.state.lock().unwrap();
+        let key = (username.to_string(), client.to_string());
+        let next_id = state.players.len() as u32 + 1;
+        let player_id = *state.players.entry(key.clone()).or_insert(next_id);
+        match state.entries.get_mut(&key) {
+            // range requests and seeking in the same song
+            Some(entry) if entry.song_id == song_id && now - entry.last_seen <= self.timeout => entry.last_seen = now,
+            _ => {
+                state.entries.insert(key, Playing {
+                    username: username.to_string(),
+                    client: client.to_string(),
+                    player_id,
+                    song_id: song_id.to_string(),
+                    started: now,
+                    last_seen: now,
+                });
+            }
+        }
+    }
+
+    /// Songs being played at `now`, most recently started first.
+    pub fn entries(&self, now: i64) -> Vec<Playing> {
+        let mut state = self.state.lock().unwrap();
+        let timeout = self.timeout;
+        state.entries.retain(|_, entry| now - entry.last_seen <= timeout);
+        let mut entries: Vec<_> = state.entries.values().cloned().collect();
+        entries.sort_by(|a, b| b.started.cmp(&a.started).then_with(|| a.player_id.cmp(&b.player_id)));
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::now_playing::Tracker;
+
+    #[test]
+    fn test_now_playing() {
+        let now_playing = Tracker::new(600);
+        now_playing.play("alice", "web", "TEST-001/1", 0);
+        now_playing.play("alice", "web", "TEST-001/1", 300);
+        now_playing.play("alice", "mobile", "TEST-002/1", 100);
+        now_playing.play("bob", "web", "TEST-003/1", 200);
+
+        let entries = now_playing.entries(400);
+        let songs: Vec<_> = entries.iter().map(|e| e.song_id.as_str()).collect();
+        assert_eq!(songs, vec!["TEST-003/1", "TEST-002/1", "TEST-001/1"]);
+        // repeated requests of the same song keep its start time
+        assert_eq!(entries[2].started, 0);
+
+        // expired entries are removed, except the one kept alive
+        let entries = now_playing.entries(850);
+        let songs: Vec<_> = entries.iter().map(|e| e.song_id.as_str()).collect();
+        assert_eq!(songs, vec!["TEST-001/1"]);
+
+        // player id is stable after switching songs
+        now_playing.play("alice", "mobile", "TEST-002/2", 900);
+        let entries = now_playing.entries(900);
+        assert_eq!(entries[0].song_id, "TEST-002/2");
+        assert_eq!(entries[0].player_id, 2);
+    }
+}
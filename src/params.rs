@@ -0,0 +1,180 @@
+use std::future::{ready, Future, Ready};
+use std::ops::Deref;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use actix_web::{dev, http, web, FromRequest, HttpMessage, HttpRequest};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use futures_util::StreamExt;
+use serde::de::DeserializeOwned;
+use crate::error;
+
+/// Maximum size of form-encoded body, large enough for thousands of `songId`s.
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// Parameters of request in urlencoded form, from query string and form-encoded body of POST requests.
+///
+/// Inserted into request extensions by [`ReadParams`], before authentication.
+#[derive(Clone)]
+pub struct Params(Rc<String>);
+
+impl Params {
+    fn new(query: &str, body: &[u8]) -> Self {
+        let body = String::from_utf8_lossy(body);
+        let params = match (query, body.as_ref()) {
+            (query, "") => query.to_string(),
+            ("", body) => body.to_string(),
+            (query, body) => format!("{}&{}", query, body),
+        };
+        Self(Rc::new(params))
+    }
+
+    pub fn from_query(query: &str) -> Self {
+        Self::new(query, &[])
+    }
+
+    /// Parameters read by [`ReadParams`], or query string if the middleware is not used.
+    pub fn of(req: &HttpRequest) -> Self {
+        match req.extensions().get::<Params>() {
+            Some(params) => params.clone(),
+            None => Self::from_query(req.query_string()),
+        }
+    }
+
+    pub fn parse<T: DeserializeOwned>(&self) -> error::Result<T> {
+        web::Query::<T>::from_query(&self.0)
+            .map(web::Query::into_inner)
+            .map_err(|e| error::Error::InvalidParameter(e.to_string()))
+    }
+
+    /// All values of a repeated parameter, like `songId=1&songId=2`.
+    pub fn all(&self, key: &str) -> Vec<String> {
+        form_urlencoded::parse(self.0.as_bytes())
+            .filter(|(k, _)| k == key)
+            .map(|(_, v)| v.into_owned())
+            .collect()
+    }
+}
+
+impl FromRequest for Params {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut dev::Payload) -> Self::Future {
+        ready(Ok(Self::of(req)))
+    }
+}
+
+/// Like `web::Query`, but also reads parameters in form-encoded body of POST requests.
+pub struct Query<T>(pub T);
+
+impl<T> Deref for Query<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned> FromRequest for Query<T> {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut dev::Payload) -> Self::Future {
+        ready(Params::of(req).parse().map(Query).map_err(Into::into))
+    }
+}
+
+/// Middleware reading form-encoded body of POST requests into [`Params`].
+pub struct ReadParams;
+
+impl<S> Transform<S, ServiceRequest> for ReadParams
+    where
+        S: Service<ServiceRequest, Response=ServiceResponse, Error=actix_web::Error> + 'static,
+        S::Future: 'static,
+{
+    type Response = ServiceResponse;
+    type Error = actix_web::Error;
+    type Transform = ReadParamsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ReadParamsMiddleware { service: Rc::new(service) }))
+    }
+}
+
+pub struct ReadParamsMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S> Service<ServiceRequest> for ReadParamsMiddleware<S>
+    where
+        S: Service<ServiceRequest, Response=ServiceResponse, Error=actix_web::Error> + 'static,
+        S::Future: 'static,
+{
+    type Response = ServiceResponse;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output=Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, ctx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(ctx)
+    }
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        Box::pin(async move {
+            let is_form = req.method() == http::Method::POST && req.content_type() == "application/x-www-form-urlencoded";
+            let mut body = web::BytesMut::new();
+            if is_form {
+                let mut payload = req.take_payload();
+                while let Some(chunk) = payload.next().await {
+                    body.extend_from_slice(&chunk?);
+                    if body.len() > MAX_BODY_SIZE {
+                        let e = error::Error::InvalidParameter("Request body is too large".to_string());
+                        return Ok(req.error_response(e));
+                    }
+                }
+            }
+            let params = Params::new(req.query_string(), &body);
+            req.extensions_mut().insert(params);
+            service.call(req).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{web, App, HttpResponse};
+    use actix_web::test::{init_service, call_service, read_body, TestRequest};
+    use serde::Deserialize;
+    use crate::params::{Params, Query, ReadParams};
+
+    #[derive(Deserialize)]
+    struct Name {
+        name: String,
+    }
+
+    async fn echo(query: Query<Name>, params: Params) -> HttpResponse {
+        HttpResponse::Ok().body(format!("{} {}", query.name, params.all("songId").join(",")))
+    }
+
+    #[test]
+    fn test_read_params() {
+        actix_web::rt::System::new().block_on(async {
+            let app = init_service(App::new()
+                .wrap(ReadParams)
+                .route("/createPlaylist.view", web::route().to(echo))
+            ).await;
+
+            let req = TestRequest::get().uri("/createPlaylist.view?name=test&songId=1&songId=2").to_request();
+            assert_eq!(read_body(call_service(&app, req).await).await, "test 1,2");
+
+            let req = TestRequest::post().uri("/createPlaylist.view?songId=1")
+                .insert_header(("Content-Type", "application/x-www-form-urlencoded"))
+                .set_payload("name=a%20b&songId=2&songId=3")
+                .to_request();
+            assert_eq!(read_body(call_service(&app, req).await).await, "a b 1,2,3");
+        });
+    }
+}
@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use rusqlite::params;
+use crate::store::Store;
+use crate::models::iso8601;
+
+/// Play counts of a user, keyed by song id and album catalog.
+pub struct Plays {
+    /// item id -> (play count, last played time)
+    items: HashMap<String, (u64, i64)>,
+}
+
+impl Plays {
+    pub fn count(&self, id: &str) -> Option<u64> {
+        self.items.get(id).map(|(count, _)| *count)
+    }
+
+    /// Last played time of item in ISO 8601.
+    pub fn played(&self, id: &str) -> Option<String> {
+        self.items.get(id).map(|(_, time)| iso8601(*time))
+    }
+
+    /// Played albums, most played first.
+    pub fn frequent_albums(&self) -> Vec<&str> {
+        self.albums_by(|(count, time)| (*count as i64, *time))
+    }
+
+    /// Played albums, most recently played first.
+    pub fn recent_albums(&self) -> Vec<&str> {
+        self.albums_by(|(_, time)| (*time, 0))
+    }
+
+    fn albums_by<F: Fn(&(u64, i64)) -> (i64, i64)>(&self, key: F) -> Vec<&str> {
+        let mut albums: Vec<_> = self.items.iter()
+            .filter(|(id, _)| !id.contains('/'))
+            .collect();
+        // ties are broken by catalog to keep the order stable
+        albums.sort_by(|(a_id, a), (b_id, b)| key(b).cmp(&key(a)).then_with(|| a_id.cmp(b_id)));
+        albums.into_iter().map(|(id, _)| id.as_str()).collect()
+    }
+}
+
+impl Store {
+    /// Record a play of song at `time`, updating play counts of both the song and its album.
+    pub fn record_play(&self, username: &str, song_id: &str, time: i64) -> rusqlite::Result<()> {
+        let catalog = song_id.split_once('/').map(|(catalog, _)| catalog).unwrap_or(song_id);
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute("INSERT INTO play_history (username, song_id, time) VALUES (?1, ?2, ?3)", params![username, song_id, time])?;
+        for item_id in [song_id, catalog] {
+            tx.execute(
+                "INSERT INTO play_count (username, item_id, count, last_played) VALUES (?1, ?2, 1, ?3)
+                ON CONFLICT (username, item_id) DO UPDATE SET count = count + 1, last_played = max(last_played, excluded.last_played)",
+                params![username, item_id, time],
+            )?;
+        }
+        tx.commit()
+    }
+
+    pub fn plays(&self, username: &str) -> rusqlite::Result<Plays> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT item_id, count, last_played FROM play_count WHERE username = ?1")?;
+        let items = stmt.query_map(params![username], |row| {
+            Ok((row.get(0)?, (row.get::<_, i64>(1)? as u64, row.get(2)?)))
+        })?.collect::<rusqlite::Result<_>>()?;
+        Ok(Plays { items })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::Store;
+    use rusqlite::NO_PARAMS;
+
+    #[test]
+    fn test_record_play() {
+        let store = Store::memory().unwrap();
+        store.record_play("alice", "TEST-001/1", 100).unwrap();
+        store.record_play("alice", "TEST-001/2", 300).unwrap();
+        store.record_play("alice", "TEST-001/1", 200).unwrap();
+        store.record_play("alice", "TEST-002/1", 400).unwrap();
+        store.record_play("alice", "TEST-003/2/1", 500).unwrap();
+        store.record_play("bob", "TEST-001/1", 500).unwrap();
+
+        let plays = store.plays("alice").unwrap();
+        assert_eq!(plays.count("TEST-001/1"), Some(2));
+        assert_eq!(plays.count("TEST-001"), Some(3));
+        assert_eq!(plays.count("TEST-003"), Some(1));
+        assert_eq!(plays.count("TEST-004"), None);
+        // plays submitted out of order should not move last played time backwards
+        assert_eq!(plays.played("TEST-001/1"), Some("1970-01-01T00:03:20Z".to_string()));
+        assert_eq!(plays.frequent_albums(), vec!["TEST-001", "TEST-003", "TEST-002"]);
+        assert_eq!(plays.recent_albums(), vec!["TEST-003", "TEST-002", "TEST-001"]);
+
+        let history: i64 = store.conn().query_row("SELECT count(*) FROM play_history", NO_PARAMS, |row| row.get(0)).unwrap();
+        assert_eq!(history, 6);
+    }
+}
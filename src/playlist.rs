@@ -0,0 +1,120 @@
+use rusqlite::{params, OptionalExtension, Row};
+use crate::store::{Store, now};
+
+pub struct PlaylistRecord {
+    pub id: i64,
+    pub owner: String,
+    pub name: String,
+    pub comment: String,
+    pub public: bool,
+    pub created: i64,
+    pub changed: i64,
+    /// song ids, including those no longer available
+    pub songs: Vec<String>,
+}
+
+impl PlaylistRecord {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get(0)?,
+            owner: row.get(1)?,
+            name: row.get(2)?,
+            comment: row.get(3)?,
+            public: row.get(4)?,
+            created: row.get(5)?,
+            changed: row.get(6)?,
+            songs: Vec::new(),
+        })
+    }
+}
+
+const PLAYLIST_COLUMNS: &str = "id, owner, name, comment, public, created, changed";
+
+impl Store {
+    /// Playlists owned by `username`, and public playlists of others if `include_public` is set.
+    pub fn playlists(&self, username: &str, include_public: bool) -> rusqlite::Result<Vec<PlaylistRecord>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM playlist WHERE owner = ?1 OR (?2 AND public) ORDER BY id", PLAYLIST_COLUMNS))?;
+        let mut playlists = stmt.query_map(params![username, include_public], PlaylistRecord::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let mut stmt = conn.prepare("SELECT song_id FROM playlist_entry WHERE playlist_id = ?1 ORDER BY position")?;
+        for playlist in playlists.iter_mut() {
+            playlist.songs = stmt.query_map(params![playlist.id], |row| row.get(0))?
+                .collect::<rusqlite::Result<_>>()?;
+        }
+        Ok(playlists)
+    }
+
+    pub fn playlist(&self, id: i64) -> rusqlite::Result<Option<PlaylistRecord>> {
+        let conn = self.conn();
+        let playlist = conn.query_row(&format!("SELECT {} FROM playlist WHERE id = ?1", PLAYLIST_COLUMNS), params![id], PlaylistRecord::from_row)
+            .optional()?;
+        match playlist {
+            Some(mut playlist) => {
+                let mut stmt = conn.prepare("SELECT song_id FROM playlist_entry WHERE playlist_id = ?1 ORDER BY position")?;
+                playlist.songs = stmt.query_map(params![id], |row| row.get(0))?
+                    .collect::<rusqlite::Result<_>>()?;
+                Ok(Some(playlist))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn create_playlist(&self, owner: &str, name: &str, songs: &[String]) -> rusqlite::Result<i64> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let now = now();
+        tx.execute("INSERT INTO playlist (owner, name, created, changed) VALUES (?1, ?2, ?3, ?3)", params![owner, name, now])?;
+        let id = tx.last_insert_rowid();
+        for (position, song) in songs.iter().enumerate() {
+            tx.execute("INSERT INTO playlist_entry (playlist_id, position, song_id) VALUES (?1, ?2, ?3)", params![id, position as i64, song])?;
+        }
+        tx.commit()?;
+        Ok(id)
+    }
+
+    /// Save playlist info and songs, and update its `changed` time.
+    pub fn update_playlist(&self, playlist: &PlaylistRecord) -> rusqlite::Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute("UPDATE playlist SET name = ?2, comment = ?3, public = ?4, changed = ?5 WHERE id = ?1",
+                   params![playlist.id, playlist.name, playlist.comment, playlist.public, now()])?;
+        tx.execute("DELETE FROM playlist_entry WHERE playlist_id = ?1", params![playlist.id])?;
+        for (position, song) in playlist.songs.iter().enumerate() {
+            tx.execute("INSERT INTO playlist_entry (playlist_id, position, song_id) VALUES (?1, ?2, ?3)", params![playlist.id, position as i64, song])?;
+        }
+        tx.commit()
+    }
+
+    pub fn delete_playlist(&self, id: i64) -> rusqlite::Result<()> {
+        self.conn().execute("DELETE FROM playlist WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::Store;
+
+    #[test]
+    fn test_playlist() {
+        let store = Store::memory().unwrap();
+        let songs = vec!["TEST-001/1".to_string(), "TEST-001/2".to_string()];
+        let id = store.create_playlist("alice", "test", &songs).unwrap();
+
+        let mut playlist = store.playlist(id).unwrap().unwrap();
+        assert_eq!(playlist.owner, "alice");
+        assert_eq!(playlist.songs, songs);
+
+        playlist.songs.reverse();
+        playlist.public = true;
+        store.update_playlist(&playlist).unwrap();
+        assert_eq!(store.playlist(id).unwrap().unwrap().songs, vec!["TEST-001/2", "TEST-001/1"]);
+
+        assert_eq!(store.playlists("bob", false).unwrap().len(), 0);
+        assert_eq!(store.playlists("bob", true).unwrap().len(), 1);
+
+        store.delete_playlist(id).unwrap();
+        assert!(store.playlist(id).unwrap().is_none());
+    }
+}
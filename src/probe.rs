@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use reqwest::header;
+use reqwest::StatusCode;
+use rusqlite::{params, OptionalExtension};
+use futures_util::{stream, StreamExt};
+use crate::backend::Backends;
+use crate::repo::Song;
+use crate::store::Store;
+
+/// Bytes needed to read STREAMINFO of flac: magic, block header and the block itself.
+const FLAC_HEADER_SIZE: usize = 4 + 4 + 34;
+
+/// Audio file information of a track in annil.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackInfo {
+    pub size: u64,
+    pub content_type: String,
+    /// Duration in seconds, only known for flac
+    pub duration: Option<u32>,
+    /// Average bitrate in kbps, only known for flac
+    pub bit_rate: Option<u32>,
+}
+
+impl TrackInfo {
+    pub fn suffix(&self) -> &str {
+        match self.content_type.as_str() {
+            "audio/flac" | "audio/x-flac" => "flac",
+            "audio/mpeg" | "audio/mp3" => "mp3",
+            "audio/ogg" => "ogg",
+            "audio/aac" => "aac",
+            "audio/mp4" | "audio/x-m4a" => "m4a",
+            "audio/wav" | "audio/x-wav" => "wav",
+            // annil serves flac by default
+            _ => "flac",
+        }
+    }
+}
+
+/// Track information of songs, keyed by song id.
+#[derive(Default)]
+pub struct TrackInfos {
+    items: HashMap<String, TrackInfo>,
+}
+
+impl TrackInfos {
+    pub fn get(&self, id: &str) -> Option<&TrackInfo> {
+        self.items.get(id)
+    }
+}
+
+/// Get track information of songs cached in store, without probing annil.
+pub fn cached(store: &Store, songs: &[Song<'_>]) -> TrackInfos {
+    let mut infos = TrackInfos::default();
+    for song in songs {
+        match store.track_info(&song.path()) {
+            Ok(Some(info)) => {
+                infos.items.insert(song.id(), info);
+            }
+            Ok(None) => {}
+            Err(e) => log::error!("Failed to load track info of {}: {}", song.id(), e),
+        }
+    }
+    infos
+}
+
+/// Get track information of songs, probing annil for those not cached in store yet.
+///
+/// Songs failed to probe are missing in result, and would be probed again next time.
+/// At most `concurrency` songs are probed at once, so that long lists do not flood annil.
+pub async fn probe(backends: &Backends, store: &Store, songs: &[Song<'_>], concurrency: usize) -> TrackInfos {
+    let mut infos = cached(store, songs);
+    let missing: Vec<_> = songs.iter()
+        .filter(|song| infos.get(&song.id()).is_none())
+        .map(|song| (song.id(), song.path()))
+        .collect();
+    let results: Vec<_> = stream::iter(missing)
+        .map(|(id, path)| async move {
+            let result = probe_path(backends, &path).await;
+            (id, path, result)
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+    for (id, path, result) in results {
+        match result {
+            Ok(info) => {
+                if let Err(e) = store.save_track_info(&path, &info) {
+                    log::error!("Failed to save track info of {}: {}", path, e);
+                }
+                infos.items.insert(id, info);
+            }
+            Err(e) => log::warn!("Failed to probe {}: {}", path, e),
+        }
+    }
+    infos
+}
+
+/// Probe file at `path` in annil, by requesting its first few bytes only.
+async fn probe_path(backends: &Backends, path: &str) -> anyhow::Result<TrackInfo> {
+    let mut response = backends
+        .request(path, |request| request.header(header::RANGE, format!("bytes=0-{}", FLAC_HEADER_SIZE - 1)))
+        .await?
+        .error_for_status()
+        .map_err(reqwest::Error::without_url)?;
+    let size = match response.status() {
+        // Content-Range: bytes 0-41/12345678
+        StatusCode::PARTIAL_CONTENT => response.headers().get(header::CONTENT_RANGE)
+            .and_then(|range| range.to_str().ok())
+            .and_then(|range| range.rsplit('/').next())
+            .and_then(|size| size.parse().ok()),
+        // range not supported, the whole file is being sent
+        _ => response.content_length(),
+    }.ok_or_else(|| anyhow::anyhow!("Unknown file size"))?;
+    let content_type = response.headers().get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .map(|content_type| content_type.split(';').next().unwrap_or_default().trim().to_string());
+
+    let mut head = Vec::with_capacity(FLAC_HEADER_SIZE);
+    while head.len() < FLAC_HEADER_SIZE {
+        match response.chunk().await? {
+            Some(chunk) => head.extend_from_slice(&chunk),
+            None => break,
+        }
+    }
+    Ok(parse(&head, size, content_type))
+}
+
+/// Build track info from the beginning of file.
+fn parse(head: &[u8], size: u64, content_type: Option<String>) -> TrackInfo {
+    let mut info = TrackInfo {
+        size,
+        content_type: content_type.unwrap_or_else(|| "application/octet-stream".to_string()),
+        duration: None,
+        bit_rate: None,
+    };
+    // STREAMINFO is always the first metadata block
+    if head.len() >= FLAC_HEADER_SIZE && &head[0..4] == b"fLaC" && head[4] & 0x7f == 0 {
+        info.content_type = "audio/flac".to_string();
+        // sample rate: 20 bits, channels: 3 bits, bits per sample: 5 bits, total samples: 36 bits
+        let mut bits = [0; 8];
+        bits.copy_from_slice(&head[18..26]);
+        let bits = u64::from_be_bytes(bits);
+        let sample_rate = bits >> 44;
+        let samples = bits & 0xf_ffff_ffff;
+        if sample_rate > 0 && samples > 0 {
+            info.duration = Some(((samples + sample_rate / 2) / sample_rate) as u32);
+            info.bit_rate = Some((size * 8 * sample_rate / samples / 1000) as u32);
+        }
+    }
+    info
+}
+
+impl Store {
+    fn track_info(&self, path: &str) -> rusqlite::Result<Option<TrackInfo>> {
+        self.conn().query_row(
+            "SELECT size, content_type, duration, bit_rate FROM track_info WHERE path = ?1",
+            params![path],
+            |row| Ok(TrackInfo {
+                size: row.get::<_, i64>(0)? as u64,
+                content_type: row.get(1)?,
+                duration: row.get(2)?,
+                bit_rate: row.get(3)?,
+            }),
+        ).optional()
+    }
+
+    fn save_track_info(&self, path: &str, info: &TrackInfo) -> rusqlite::Result<()> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO track_info (path, size, content_type, duration, bit_rate) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![path, info.size as i64, info.content_type, info.duration, info.bit_rate],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::probe::{parse, TrackInfo, FLAC_HEADER_SIZE};
+    use crate::store::Store;
+
+    /// Flac header with STREAMINFO of 44100Hz, 2 channels, 16 bits, 3 minutes.
+    fn flac_header() -> Vec<u8> {
+        let mut head = b"fLaC".to_vec();
+        // last metadata block, STREAMINFO, 34 bytes
+        head.extend_from_slice(&[0x80, 0, 0, 34]);
+        // block sizes and frame sizes
+        head.extend_from_slice(&[0; 10]);
+        let samples = 44100 * 180;
+        let bits: u64 = 44100 << 44 | 1 << 41 | 15 << 36 | samples;
+        head.extend_from_slice(&bits.to_be_bytes());
+        // md5
+        head.extend_from_slice(&[0; 16]);
+        assert_eq!(head.len(), FLAC_HEADER_SIZE);
+        head
+    }
+
+    #[test]
+    fn test_parse() {
+        let info = parse(&flac_header(), 18_000_000, Some("application/octet-stream".to_string()));
+        assert_eq!(info, TrackInfo {
+            size: 18_000_000,
+            content_type: "audio/flac".to_string(),
+            duration: Some(180),
+            bit_rate: Some(800),
+        });
+        assert_eq!(info.suffix(), "flac");
+
+        let info = parse(b"ID3", 1000, Some("audio/mpeg".to_string()));
+        assert_eq!(info.duration, None);
+        assert_eq!(info.suffix(), "mp3");
+    }
+
+    #[test]
+    fn test_cache() {
+        let store = Store::memory().unwrap();
+        assert_eq!(store.track_info("TEST-001/1").unwrap(), None);
+        let info = parse(&flac_header(), 18_000_000, None);
+        store.save_track_info("TEST-001/1", &info).unwrap();
+        assert_eq!(store.track_info("TEST-001/1").unwrap(), Some(info));
+    }
+}
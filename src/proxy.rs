@@ -0,0 +1,83 @@
+use actix_web::{HttpRequest, HttpResponse};
+use actix_web::http::{header, StatusCode};
+use crate::backend::Backends;
+use crate::error::{self, Error};
+
+/// Request headers forwarded to annil.
+const REQUEST_HEADERS: [header::HeaderName; 4] = [
+    header::RANGE,
+    header::IF_RANGE,
+    header::IF_NONE_MATCH,
+    header::IF_MODIFIED_SINCE,
+];
+
+/// Response headers forwarded to client, except `Content-Length`.
+const RESPONSE_HEADERS: [header::HeaderName; 6] = [
+    header::CONTENT_TYPE,
+    header::CONTENT_RANGE,
+    header::ACCEPT_RANGES,
+    header::ETAG,
+    header::LAST_MODIFIED,
+    header::CACHE_CONTROL,
+];
+
+/// Serve `path` on annil to client.
+///
+/// By default the file is fetched from annil and piped back to client, so annil token is never exposed.
+/// If `redirect` is enabled in config of the preferred backend, client is redirected to annil directly instead.
+/// Songs missing in annil are [`Error::NotFound`], instead of `404 Not Found` which clients take as unsupported endpoints.
+pub async fn serve(backends: &Backends, path: &str, req: &HttpRequest) -> error::Result<HttpResponse> {
+    if let Some(backend) = backends.route(path).first().filter(|backend| backend.config.redirect) {
+        return Ok(HttpResponse::Found()
+            .append_header((header::LOCATION, backend.config.get_url(path)))
+            .finish());
+    }
+
+    let request = backends.request(path, |mut request| {
+        for name in REQUEST_HEADERS.iter() {
+            if let Some(value) = req.headers().get(name) {
+                request = request.header(name.as_str(), value.as_bytes());
+            }
+        }
+        request
+    });
+    let response = match request.await {
+        Ok(response) if response.status() == reqwest::StatusCode::NOT_FOUND => return Err(Error::not_found("Song")),
+        Ok(response) => response,
+        Err(e) => {
+            log::error!("Failed to fetch {} from annil: {}", path, e);
+            return Ok(HttpResponse::BadGateway().finish());
+        }
+    };
+
+    let status = StatusCode::from_u16(response.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+    let mut builder = HttpResponse::build(status);
+    for name in RESPONSE_HEADERS.iter() {
+        if let Some(value) = response.headers().get(name.as_str()) {
+            builder.insert_header((name.clone(), value.as_bytes()));
+        }
+    }
+    Ok(match response.content_length() {
+        // keep Content-Length so that clients know the size before buffering
+        Some(length) => builder.no_chunking(length).streaming(response.bytes_stream()),
+        None => builder.streaming(response.bytes_stream()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use crate::backend::Backends;
+    use crate::proxy::serve;
+    use crate::test_util;
+
+    #[test]
+    fn test_not_found() {
+        actix_web::rt::System::new().block_on(async {
+            let (url, _) = test_util::mock_server(|_| (404, String::new()));
+            let backends = Backends::new(vec![toml::from_str(&format!("server = \"{}\"\ntoken = \"token\"", url)).unwrap()]);
+            let result = serve(&backends, "TEST-001/1", &TestRequest::default().to_http_request()).await;
+            assert_eq!(result.err().map(|e| e.code()), Some(70));
+        });
+    }
+}
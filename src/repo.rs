@@ -1,35 +1,115 @@
 use anni_repo::{Album, RepositoryManager};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use anyhow::Context;
 use anni_repo::category::Category;
+use anni_repo::album::Track;
+use crate::search::SearchIndex;
+
+/// Artist derived from album and track artists.
+pub struct Artist {
+    pub id: String,
+    pub name: String,
+    /// catalogs of albums the artist takes part in, sorted
+    pub albums: Vec<String>,
+}
+
+/// Stable artist id generated from artist name.
+pub fn artist_id(name: &str) -> String {
+    format!("ar-{:x}", md5::compute(name))
+}
+
+/// A track in album, located by 1-based disc and track number.
+pub struct Song<'a> {
+    pub album: &'a Album,
+    pub disc_id: usize,
+    pub track_id: usize,
+    pub track: &'a Track,
+}
+
+impl Song<'_> {
+    /// Song id exposed to clients.
+    ///
+    /// Songs are `{catalog}/{track_id}` in albums with one disc,
+    /// and `{catalog}/{disc_id}/{track_id}` in multi-disc albums.
+    pub fn id(&self) -> String {
+        if self.album.discs().len() == 1 {
+            format!("{}/{}", self.album.catalog(), self.track_id)
+        } else {
+            format!("{}/{}/{}", self.album.catalog(), self.disc_id, self.track_id)
+        }
+    }
+
+    /// Path of song in annil, where discs of multi-disc albums have their own catalogs.
+    pub fn path(&self) -> String {
+        if self.album.discs().len() == 1 {
+            format!("{}/{}", self.album.catalog(), self.track_id)
+        } else {
+            format!("{}/{}", self.album.discs()[self.disc_id - 1].catalog(), self.track_id)
+        }
+    }
+}
+
+/// All songs of album, in disc order.
+pub fn songs(album: &Album) -> impl Iterator<Item=Song<'_>> {
+    album.discs().iter().enumerate().flat_map(move |(disc_id, disc)| {
+        disc.tracks().iter().enumerate().map(move |(track_id, track)| Song {
+            album,
+            disc_id: disc_id + 1,
+            track_id: track_id + 1,
+            track,
+        })
+    })
+}
+
+/// Number of repositories loaded so far, see [`RepoManager::generation`].
+static GENERATION: AtomicU64 = AtomicU64::new(0);
 
 pub struct RepoManager {
+    /// Unique among loaded repositories, newer ones have greater generations
+    generation: u64,
+    /// album catalog -> album, multi-disc albums are split into discs if `split_discs` is set
     albums: HashMap<String, Album>,
-    discs: HashMap<String, Album>,
-    /// one album catalog -> multi disc catalog map
+    /// disc catalog -> (album catalog, 1-based disc id), for discs of multi-disc albums
+    discs: HashMap<String, (String, usize)>,
+    /// album catalog -> disc catalogs, for multi-disc albums split into discs
     multi_map: HashMap<String, Vec<String>>,
     categories: HashMap<String, Category>,
+    /// artist id -> artist
+    artists: HashMap<String, Artist>,
+    index: SearchIndex,
 }
 
 impl RepoManager {
-    pub fn new<P: AsRef<Path>>(root: P) -> Self {
-        let manager = RepositoryManager::new(root).expect("Invalid Anni Metadata Repository");
+    /// Load metadata repository at `root`.
+    ///
+    /// Multi-disc albums are kept as one album, unless `split_discs` is set,
+    /// in which case each disc is presented as an album titled `{title} [Disc N]`.
+    pub fn new<P: AsRef<Path>>(root: P, split_discs: bool) -> anyhow::Result<Self> {
+        Self::load(root, split_discs, |_| {})
+    }
+
+    /// Load metadata repository at `root`, calling `progress` with the number of albums loaded so far.
+    pub fn load<P: AsRef<Path>, F: Fn(usize)>(root: P, split_discs: bool, progress: F) -> anyhow::Result<Self> {
+        let manager = RepositoryManager::new(root).context("Invalid Anni Metadata Repository")?;
 
         let mut albums = HashMap::new();
         let mut discs = HashMap::new();
         let mut multi_map = HashMap::new();
-        for catalog in manager.catalogs().unwrap() {
-            let album = manager.load_album(&catalog).unwrap();
+        for (loaded, catalog) in manager.catalogs()?.into_iter().enumerate() {
+            progress(loaded);
+            let album = manager.load_album(&catalog).with_context(|| format!("Failed to load album {}", catalog))?;
             if album.discs().len() == 1 {
                 albums.insert(album.catalog().to_string(), album);
-            } else {
+            } else if split_discs {
                 let album_catalog = album.catalog().to_string();
                 let release_date = album.release_date().clone();
                 let mut disc_catalogs = Vec::new();
                 for (i, disc) in album.into_discs().into_iter().enumerate() {
                     let title = disc.title().to_string();
                     disc_catalogs.push(disc.catalog().to_string());
-                    discs.insert(
+                    albums.insert(
                         disc.catalog().to_string(),
                         disc.into_album(
                             format!("{} [Disc {}]", title, i + 1),
@@ -38,19 +118,90 @@ impl RepoManager {
                     );
                 }
                 multi_map.insert(album_catalog, disc_catalogs);
+            } else {
+                for (i, disc) in album.discs().iter().enumerate() {
+                    discs.insert(disc.catalog().to_string(), (album.catalog().to_string(), i + 1));
+                }
+                albums.insert(album.catalog().to_string(), album);
             }
         }
 
         let mut categories = HashMap::new();
-        for category in manager.categories().unwrap() {
-            let category = manager.load_category(&category).unwrap();
+        for category in manager.categories()? {
+            let category = manager.load_category(&category).with_context(|| format!("Failed to load category {}", category))?;
             categories.insert(category.info().name().to_string(), category);
         }
-        Self { albums, discs, multi_map, categories }
+        let mut all: Vec<_> = albums.values().collect();
+        all.sort_by(|a, b| a.catalog().cmp(b.catalog()));
+        let mut artists: HashMap<String, Artist> = HashMap::new();
+        for album in all.iter() {
+            let names = std::iter::once(album.artist())
+                .chain(album.discs().iter().flat_map(|disc| disc.tracks().iter().map(|track| track.artist())));
+            for name in names {
+                let artist = artists.entry(artist_id(name)).or_insert_with(|| Artist {
+                    id: artist_id(name),
+                    name: name.to_string(),
+                    albums: Vec::new(),
+                });
+                // albums are sorted, so duplicated catalogs must be adjacent
+                if artist.albums.last().map(|c| c.as_str()) != Some(album.catalog()) {
+                    artist.albums.push(album.catalog().to_string());
+                }
+            }
+        }
+
+        let index = SearchIndex::new(all.into_iter(), artists.values());
+        let generation = GENERATION.fetch_add(1, Ordering::Relaxed) + 1;
+        Ok(Self { generation, albums, discs, multi_map, categories, artists, index })
+    }
+
+    /// Tells repositories apart, as results resolved in a previous one must not be reused, see [`Backends::albums`].
+    ///
+    /// [`Backends::albums`]: crate::backend::Backends::albums
+    pub fn generation(&self) -> u64 {
+        self.generation
     }
 
     pub fn load_album(&self, catalog: &str) -> Option<&Album> {
-        self.discs.get(catalog).map(|a| Some(a)).unwrap_or(self.albums.get(catalog))
+        self.albums.get(catalog)
+    }
+
+    /// Load song by id, see [Song::id].
+    ///
+    /// `{disc_catalog}/{track_id}` is also accepted for songs in multi-disc albums,
+    /// which is the id used before multi-disc albums were merged.
+    pub fn load_track(&self, id: &str) -> Option<Song<'_>> {
+        let (prefix, track_id) = id.rsplit_once('/')?;
+        let track_id: usize = track_id.parse().ok()?;
+        let (album, disc_id) = match (self.albums.get(prefix), self.discs.get(prefix)) {
+            (Some(album), _) if album.discs().len() == 1 => (album, 1),
+            (_, Some((catalog, disc_id))) => (self.albums.get(catalog)?, *disc_id),
+            _ => {
+                let (catalog, disc_id) = prefix.rsplit_once('/')?;
+                (self.albums.get(catalog)?, disc_id.parse().ok()?)
+            }
+        };
+        let track = album.discs().get(disc_id.checked_sub(1)?)?.tracks().get(track_id.checked_sub(1)?)?;
+        Some(Song { album, disc_id, track_id, track })
+    }
+
+    /// Current id of song or album `id` exposed by earlier versions, `None` if `id` is current or unknown.
+    ///
+    /// Songs of multi-disc albums used to be `{disc_catalog}/{track_id}`, and their plays were counted for `{disc_catalog}`.
+    pub fn current_id(&self, id: &str) -> Option<String> {
+        let current = match id.contains('/') {
+            true => self.load_track(id)?.id(),
+            false => self.discs.get(id)?.0.clone(),
+        };
+        (current != id).then_some(current)
+    }
+
+    /// Path of album cover in annil, multi-disc albums use cover of the first disc.
+    pub fn cover_path(&self, catalog: &str) -> String {
+        match self.albums.get(catalog) {
+            Some(album) if album.discs().len() > 1 => format!("{}/cover", album.discs()[0].catalog()),
+            _ => format!("{}/cover", catalog),
+        }
     }
 
     pub fn load_albums(&self, catalog: &str) -> Vec<&Album> {
@@ -61,10 +212,42 @@ impl RepoManager {
         }
     }
 
+    pub fn albums(&self) -> impl Iterator<Item=&Album> {
+        self.albums.values()
+    }
+
+    /// Catalogs of albums available in annil, from catalogs of albums and discs in annil.
+    pub fn available(&self, catalogs: Vec<String>) -> Vec<String> {
+        let mut multi_disc = HashSet::new();
+        let mut result = Vec::with_capacity(catalogs.len());
+        for catalog in catalogs {
+            match self.discs.get(&catalog) {
+                // a multi-disc album is available if any of its discs is
+                Some((album, _)) => if multi_disc.insert(album) {
+                    result.push(album.clone());
+                },
+                None => result.push(catalog),
+            }
+        }
+        result
+    }
+
     pub fn load_category(&self, category: &str) -> Option<&Category> {
         self.categories.get(category)
     }
 
+    pub fn load_artist(&self, id: &str) -> Option<&Artist> {
+        self.artists.get(id)
+    }
+
+    pub fn artists(&self) -> impl Iterator<Item=&Artist> {
+        self.artists.values()
+    }
+
+    pub fn index(&self) -> &SearchIndex {
+        &self.index
+    }
+
     pub fn categories(&self) -> impl Iterator<Item=(&str, &Category)> {
         self.categories.iter().map(|(k, v)| (k.as_str(), v))
     }
@@ -1,23 +1,221 @@
-use actix_web::{dev, Result, body::Body};
+use actix_web::{dev, Result, HttpRequest, HttpResponse, Responder};
 use actix_web::middleware::ErrorHandlerResponse;
-use actix_web::http::StatusCode;
 use actix_web::body::AnyBody;
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
+use crate::models::SonicError;
+use crate::params::Params;
 
-pub fn ok(mut body: String) -> String {
-    body.insert_str(0, r#"<subsonic-response xmlns="http://subsonic.org/restapi" status="ok" version="1.15.0">
-"#);
-    body.push_str(r#"
-</subsonic-response>"#);
-    body
+pub const VERSION: &str = "1.15.0";
+/// Server name reported in `type` of OpenSubsonic envelope
+pub const SERVER_TYPE: &str = "annisonic";
+pub const SERVER_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// A model which can be placed directly under `<subsonic-response>`.
+///
+/// `NAME` must match the serde name of the struct, as XML takes the element name from serde
+/// while JSON needs it as the key in the envelope.
+pub trait Body: Serialize {
+    const NAME: &'static str;
+}
+
+impl Body for () {
+    const NAME: &'static str = "";
+}
+
+#[derive(Deserialize)]
+struct FormatQuery {
+    #[serde(rename = "f", default)]
+    format: String,
+    callback: Option<String>,
+}
+
+/// Response format requested by the client with the `f` parameter.
+pub enum Format {
+    Xml,
+    Json,
+    Jsonp(String),
+    /// JSONP with a callback which is not a JavaScript identifier, answered with an error in JSON
+    InvalidCallback,
+}
+
+impl Format {
+    pub fn from_request(req: &HttpRequest) -> Self {
+        match Params::of(req).parse::<FormatQuery>() {
+            Ok(query) => {
+                match (query.format.as_str(), query.callback) {
+                    ("json", _) => Format::Json,
+                    // jsonp without callback is useless, fallback to json
+                    ("jsonp", Some(callback)) if is_callback(&callback) => Format::Jsonp(callback),
+                    // reflected into javascript, so anything else could inject scripts
+                    ("jsonp", Some(_)) => Format::InvalidCallback,
+                    ("jsonp", None) => Format::Json,
+                    _ => Format::Xml,
+                }
+            }
+            Err(_) => Format::Xml,
+        }
+    }
+
+    pub fn render<T: Body>(&self, status: &str, body: Option<&T>) -> HttpResponse {
+        match self {
+            Format::Xml => {
+                let inner = match body {
+                    Some(body) => quick_xml::se::to_string(body).unwrap(),
+                    None => String::new(),
+                };
+                HttpResponse::Ok()
+                    .content_type("application/xml")
+                    .body(format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<subsonic-response xmlns="http://subsonic.org/restapi" status="{}" version="{}" type="{}" serverVersion="{}" openSubsonic="true">
+{}
+</subsonic-response>"#, status, VERSION, SERVER_TYPE, SERVER_VERSION, inner))
+            }
+            Format::Json => HttpResponse::Ok()
+                .content_type("application/json")
+                .body(to_json(status, body).to_string()),
+            Format::Jsonp(callback) => HttpResponse::Ok()
+                .content_type("application/javascript")
+                .body(format!("{}({});", callback, to_json(status, body))),
+            Format::InvalidCallback => {
+                let error = SonicError { code: 10, message: "Invalid parameter: callback".to_string() };
+                HttpResponse::Ok()
+                    .content_type("application/json")
+                    .body(to_json("failed", Some(&error)).to_string())
+            }
+        }
+    }
+}
+
+/// Whether `callback` matches `^[A-Za-z_$][A-Za-z0-9_$.]*$`.
+fn is_callback(callback: &str) -> bool {
+    let mut chars = callback.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '$')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$' || c == '.')
+}
+
+fn to_json<T: Body>(status: &str, body: Option<&T>) -> Value {
+    let mut inner = json!({
+        "status": status,
+        "version": VERSION,
+        "type": SERVER_TYPE,
+        "serverVersion": SERVER_VERSION,
+        "openSubsonic": true,
+    });
+    if let Some(body) = body {
+        let mut value = serde_json::to_value(body).unwrap();
+        fix_text_value(&mut value);
+        inner[T::NAME] = value;
+    }
+    json!({ "subsonic-response": inner })
+}
+
+/// quick-xml uses `$value` for text content, which is called `value` in subsonic json.
+/// Objects with text content only are replaced by the content itself.
+fn fix_text_value(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            if let Some(text) = map.remove("$value") {
+                if map.is_empty() {
+                    *value = text;
+                    return;
+                }
+                map.insert("value".to_owned(), text);
+            }
+            map.values_mut().for_each(fix_text_value);
+        }
+        Value::Array(array) => array.iter_mut().for_each(fix_text_value),
+        _ => {}
+    }
+}
+
+pub struct SonicResponse<T> {
+    status: &'static str,
+    body: Option<T>,
+}
+
+impl<T: Body> Responder for SonicResponse<T> {
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse {
+        Format::from_request(req).render(self.status, self.body.as_ref())
+    }
+}
+
+pub fn ok<T: Body>(body: T) -> SonicResponse<T> {
+    SonicResponse { status: "ok", body: Some(body) }
+}
+
+pub fn empty() -> SonicResponse<()> {
+    SonicResponse { status: "ok", body: None }
+}
+
+pub fn failed(code: u32, message: String) -> SonicResponse<SonicError> {
+    SonicResponse { status: "failed", body: Some(SonicError { code, message }) }
 }
 
 pub fn gone(res: dev::ServiceResponse) -> Result<ErrorHandlerResponse<AnyBody>> {
     let url = res.request().uri().to_string();
-    let mut res = res.map_body(|_, _| Body::from(format!(r#"<?xml version="1.0" encoding="UTF-8"?>
-<subsonic-response xmlns="http://subsonic.org/restapi" status="failed" version="1.15.0">
-   <error code="30" message="{}"/>
-</subsonic-response>"#, url))
-    );
-    *res.response_mut().status_mut() = StatusCode::OK;
-    Ok(ErrorHandlerResponse::Response(res))
-}
\ No newline at end of file
+    let response = failed(30, url).respond_to(res.request());
+    Ok(ErrorHandlerResponse::Response(res.into_response(response)))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use crate::response::{is_callback, to_json, Body, Format, SERVER_VERSION};
+    use crate::models::{User, OpenSubsonicExtension};
+    use crate::config::{UserConfig, Role};
+    use serde_json::json;
+
+    #[test]
+    fn test_json_envelope() {
+        assert_eq!(to_json::<()>("ok", None), json!({
+            "subsonic-response": {
+                "status": "ok",
+                "version": "1.15.0",
+                "type": "annisonic",
+                "serverVersion": SERVER_VERSION,
+                "openSubsonic": true,
+            }
+        }));
+    }
+
+    #[test]
+    fn test_json_text_value() {
+        let user = User::from_config(&UserConfig {
+            username: "test".to_owned(),
+            password: "test".to_owned(),
+            roles: vec![Role::Stream],
+            folders: vec!["@".to_owned()],
+            scrobble: Vec::new(),
+        });
+        let json = to_json("ok", Some(&user));
+        assert_eq!(json["subsonic-response"][User::NAME]["folder"], json!(["@"]));
+    }
+
+    #[test]
+    fn test_json_extensions() {
+        let extensions = vec![OpenSubsonicExtension::new("apiKeyAuthentication", &[1])];
+        let json = to_json("ok", Some(&extensions));
+        assert_eq!(json["subsonic-response"]["openSubsonicExtensions"], json!([
+            { "name": "apiKeyAuthentication", "versions": [1] }
+        ]));
+    }
+
+    #[test]
+    fn test_jsonp_callback() {
+        assert!(is_callback("jQuery_123.cb$"));
+        assert!(!is_callback(""));
+        assert!(!is_callback("1cb"));
+        assert!(!is_callback("alert(1)//"));
+
+        let req = TestRequest::get().uri("/ping.view?f=jsonp&callback=alert(document.cookie)").to_http_request();
+        assert!(matches!(Format::from_request(&req), Format::InvalidCallback));
+        let body = Format::from_request(&req).render::<()>("ok", None).into_body();
+        let body = match body {
+            actix_web::body::AnyBody::Bytes(bytes) => bytes,
+            _ => unreachable!(),
+        };
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["subsonic-response"]["error"]["code"], 10);
+    }
+}
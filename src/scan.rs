@@ -0,0 +1,71 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use crate::models::ScanStatus;
+use crate::repo::RepoManager;
+
+/// Rescans metadata repository in background.
+pub struct Scanner {
+    scanning: AtomicBool,
+    /// Albums loaded in the running scan, or albums in repository if not scanning
+    count: Arc<AtomicUsize>,
+}
+
+impl Scanner {
+    pub fn new(count: usize) -> Self {
+        Self {
+            scanning: AtomicBool::new(false),
+            count: Arc::new(AtomicUsize::new(count)),
+        }
+    }
+
+    /// Mark a scan as started, returns `false` if one is already running.
+    pub fn start(&self) -> bool {
+        self.scanning.compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire).is_ok()
+    }
+
+    /// Load metadata repository at `root` in a blocking thread, after [`Scanner::start`] succeeded.
+    pub async fn scan(&self, root: String, split_discs: bool) -> anyhow::Result<RepoManager> {
+        let count = self.count.clone();
+        let previous = count.swap(0, Ordering::Relaxed);
+        let result = tokio::task::spawn_blocking(move || {
+            RepoManager::load(root, split_discs, |loaded| count.store(loaded, Ordering::Relaxed))
+        }).await.map_err(anyhow::Error::from).and_then(|result| result);
+        let result = match result {
+            Ok(repo) => {
+                self.count.store(repo.albums().count(), Ordering::Relaxed);
+                Ok(repo)
+            }
+            // the previous repository is kept by caller, so is its count
+            Err(e) => {
+                self.count.store(previous, Ordering::Relaxed);
+                Err(e.context("Failed to load metadata repository"))
+            }
+        };
+        self.scanning.store(false, Ordering::Release);
+        result
+    }
+
+    pub fn status(&self) -> ScanStatus {
+        ScanStatus {
+            scanning: self.scanning.load(Ordering::Acquire),
+            count: self.count.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scan::Scanner;
+
+    #[test]
+    fn test_scan_status() {
+        let scanner = Scanner::new(10);
+        assert!(!scanner.status().scanning);
+        assert!(scanner.start());
+        // only one scan at a time
+        assert!(!scanner.start());
+        let status = scanner.status();
+        assert!(status.scanning);
+        assert_eq!(status.count, 10);
+    }
+}
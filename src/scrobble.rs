@@ -0,0 +1,326 @@
+use std::time::Duration;
+use reqwest::header::AUTHORIZATION;
+use reqwest::StatusCode;
+use rusqlite::params;
+use serde_json::json;
+use crate::config::{ScrobbleConfig, UserConfig};
+use crate::store::{Store, now};
+use crate::user::Users;
+
+/// Interval to check for queued scrobbles which are due.
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+/// Delay between retries of a failed scrobble in seconds, doubled after every failed retry.
+const RETRY_DELAY: i64 = 60;
+const MAX_RETRY_DELAY: i64 = 24 * 60 * 60;
+/// Queued scrobbles are dropped after this many failed retries.
+const MAX_ATTEMPTS: i64 = 20;
+
+/// A song played by user, as submitted to scrobbling services.
+pub struct Listen {
+    pub artist: String,
+    pub track: String,
+    pub album: String,
+    /// Unix timestamp when the song started playing
+    pub time: i64,
+}
+
+impl Listen {
+    pub fn new(album: &anni_repo::Album, track: &anni_repo::album::Track, time: i64) -> Self {
+        Self {
+            artist: track.artist().to_owned(),
+            track: track.title().to_owned(),
+            album: album.title().to_owned(),
+            time,
+        }
+    }
+}
+
+enum SubmitError {
+    /// Rejected by service, retrying would not help.
+    Rejected(String),
+    /// Network or server error, which may succeed later.
+    Failed(anyhow::Error),
+}
+
+/// Forwards plays to scrobbling services configured by each user.
+#[derive(Default)]
+pub struct Scrobbler {
+    client: reqwest::Client,
+}
+
+impl Scrobbler {
+    /// Notify services that user started playing a song. Failures are not retried.
+    pub async fn now_playing(&self, user: &UserConfig, listen: &Listen) {
+        for service in user.scrobble.iter() {
+            match self.submit(service, listen, true).await {
+                Ok(()) => {}
+                Err(SubmitError::Rejected(e)) => log::warn!("Now playing of {} rejected by {}: {}", user.username, service.url(), e),
+                Err(SubmitError::Failed(e)) => log::info!("Failed to submit now playing of {} to {}: {}", user.username, service.url(), e),
+            }
+        }
+    }
+
+    /// Submit a finished play to services, failed submissions are queued for retry.
+    pub async fn scrobble(&self, store: &Store, user: &UserConfig, listen: &Listen) -> rusqlite::Result<()> {
+        for service in user.scrobble.iter() {
+            match self.submit(service, listen, false).await {
+                Ok(()) => {}
+                Err(SubmitError::Rejected(e)) => log::warn!("Scrobble of {} rejected by {}: {}", user.username, service.url(), e),
+                Err(SubmitError::Failed(e)) => {
+                    log::info!("Failed to scrobble for {} to {}, queued for retry: {}", user.username, service.url(), e);
+                    store.enqueue_scrobble(&user.username, service.url(), listen)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Retry queued scrobbles which are due.
+    pub async fn flush(&self, store: &Store, users: &Users) -> rusqlite::Result<()> {
+        let now = now();
+        for queued in store.due_scrobbles(now)? {
+            // user or service may have been removed from config since queued
+            let service = users.get(&queued.username)
+                .and_then(|user| user.scrobble.iter().find(|service| service.url() == queued.service));
+            let service = match service {
+                Some(service) => service,
+                None => {
+                    store.remove_scrobble(queued.id)?;
+                    continue;
+                }
+            };
+            match self.submit(service, &queued.listen, false).await {
+                Ok(()) => store.remove_scrobble(queued.id)?,
+                Err(SubmitError::Rejected(e)) => {
+                    log::warn!("Queued scrobble of {} rejected by {}: {}", queued.username, queued.service, e);
+                    store.remove_scrobble(queued.id)?;
+                }
+                Err(SubmitError::Failed(e)) if queued.attempts + 1 >= MAX_ATTEMPTS => {
+                    log::warn!("Dropping scrobble of {} to {} after {} attempts: {}", queued.username, queued.service, MAX_ATTEMPTS, e);
+                    store.remove_scrobble(queued.id)?;
+                }
+                Err(SubmitError::Failed(_)) => store.retry_scrobble(queued.id, now + retry_delay(queued.attempts + 1))?,
+            }
+        }
+        Ok(())
+    }
+
+    async fn submit(&self, service: &ScrobbleConfig, listen: &Listen, now_playing: bool) -> Result<(), SubmitError> {
+        let request = match service {
+            ScrobbleConfig::ListenBrainz { url, token } => {
+                let mut payload = json!({
+                    "track_metadata": {
+                        "artist_name": listen.artist,
+                        "track_name": listen.track,
+                        "release_name": listen.album,
+                    },
+                });
+                if !now_playing {
+                    payload["listened_at"] = json!(listen.time);
+                }
+                self.client.post(format!("{}/1/submit-listens", url.trim_end_matches('/')))
+                    .header(AUTHORIZATION, format!("Token {}", token))
+                    .json(&json!({
+                        "listen_type": if now_playing { "playing_now" } else { "single" },
+                        "payload": [payload],
+                    }))
+            }
+            ScrobbleConfig::LastFm { url, api_key, api_secret, session_key } => {
+                let timestamp = listen.time.to_string();
+                let mut params = vec![
+                    ("method", if now_playing { "track.updateNowPlaying" } else { "track.scrobble" }),
+                    ("artist", &listen.artist),
+                    ("track", &listen.track),
+                    ("album", &listen.album),
+                    ("api_key", api_key),
+                    ("sk", session_key),
+                ];
+                if !now_playing {
+                    params.push(("timestamp", &timestamp));
+                }
+                let signature = lastfm_signature(&params, api_secret);
+                params.push(("api_sig", &signature));
+                params.push(("format", "json"));
+                self.client.post(url.as_str()).form(&params)
+            }
+        };
+
+        let response = request.send().await.map_err(|e| SubmitError::Failed(e.into()))?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS {
+            Err(SubmitError::Rejected(format!("{}: {}", status, response.text().await.unwrap_or_default())))
+        } else {
+            Err(SubmitError::Failed(anyhow::anyhow!("{}", status)))
+        }
+    }
+}
+
+/// `api_sig` of Last.fm: md5 of sorted parameters concatenated with secret.
+fn lastfm_signature(params: &[(&str, &str)], secret: &str) -> String {
+    let mut params = params.to_vec();
+    params.sort_unstable();
+    let mut data = String::new();
+    for (key, value) in params {
+        data.push_str(key);
+        data.push_str(value);
+    }
+    data.push_str(secret);
+    format!("{:x}", md5::compute(data))
+}
+
+/// Delay before retrying a scrobble whose last `attempts` retries failed.
+fn retry_delay(attempts: i64) -> i64 {
+    (RETRY_DELAY << attempts.min(16)).min(MAX_RETRY_DELAY)
+}
+
+struct QueuedScrobble {
+    id: i64,
+    username: String,
+    /// Url of service, see [ScrobbleConfig::url]
+    service: String,
+    listen: Listen,
+    attempts: i64,
+}
+
+impl Store {
+    /// Queue a scrobble, which is retried on next flush.
+    fn enqueue_scrobble(&self, username: &str, service: &str, listen: &Listen) -> rusqlite::Result<()> {
+        self.conn().execute(
+            "INSERT INTO scrobble_queue (username, service, artist, track, album, time, next_retry) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![username, service, listen.artist, listen.track, listen.album, listen.time, now()],
+        )?;
+        Ok(())
+    }
+
+    fn due_scrobbles(&self, now: i64) -> rusqlite::Result<Vec<QueuedScrobble>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT id, username, service, artist, track, album, time, attempts FROM scrobble_queue
+            WHERE next_retry <= ?1 ORDER BY id",
+        )?;
+        let rows = stmt.query_map(params![now], |row| {
+            Ok(QueuedScrobble {
+                id: row.get(0)?,
+                username: row.get(1)?,
+                service: row.get(2)?,
+                listen: Listen {
+                    artist: row.get(3)?,
+                    track: row.get(4)?,
+                    album: row.get(5)?,
+                    time: row.get(6)?,
+                },
+                attempts: row.get(7)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    fn retry_scrobble(&self, id: i64, next_retry: i64) -> rusqlite::Result<()> {
+        self.conn().execute(
+            "UPDATE scrobble_queue SET attempts = attempts + 1, next_retry = ?2 WHERE id = ?1",
+            params![id, next_retry],
+        )?;
+        Ok(())
+    }
+
+    fn remove_scrobble(&self, id: i64) -> rusqlite::Result<()> {
+        self.conn().execute("DELETE FROM scrobble_queue WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{mpsc, Mutex};
+    use crate::scrobble::{Listen, Scrobbler, lastfm_signature};
+    use crate::store::Store;
+    use crate::test_util;
+    use crate::user::Users;
+
+    /// Answer requests with each of `statuses` in order, and send received requests back.
+    fn mock_server(statuses: Vec<u16>) -> (String, mpsc::Receiver<String>) {
+        let statuses = Mutex::new(statuses.into_iter());
+        test_util::mock_server(move |_| (statuses.lock().unwrap().next().unwrap(), String::new()))
+    }
+
+    fn users(service: &str) -> Users {
+        Users::new(&test_util::config(&format!("[[users.scrobble]]\n{}", service)))
+    }
+
+    fn listen() -> Listen {
+        Listen {
+            artist: "Aimer".to_string(),
+            track: "カタオモイ".to_string(),
+            album: "Sun Dance".to_string(),
+            time: 1000,
+        }
+    }
+
+    fn queued(store: &Store) -> usize {
+        store.due_scrobbles(i64::MAX).unwrap().len()
+    }
+
+    #[test]
+    fn test_listenbrainz() {
+        let (url, requests) = mock_server(vec![200, 200]);
+        let users = users(&format!("service = \"listenbrainz\"\nurl = \"{}\"\ntoken = \"abc\"", url));
+        let user = users.get("alice").unwrap();
+        let store = Store::memory().unwrap();
+        let scrobbler = Scrobbler::default();
+        actix_web::rt::System::new().block_on(async {
+            scrobbler.now_playing(user, &listen()).await;
+            scrobbler.scrobble(&store, user, &listen()).await.unwrap();
+        });
+
+        let request = requests.recv().unwrap();
+        assert!(request.starts_with("POST /1/submit-listens "));
+        assert!(request.to_lowercase().contains("authorization: token abc"));
+        assert!(request.contains(r#""listen_type":"playing_now""#));
+        assert!(!request.contains("listened_at"));
+        let request = requests.recv().unwrap();
+        assert!(request.contains(r#""listen_type":"single""#));
+        assert!(request.contains(r#""listened_at":1000"#));
+        assert!(request.contains(r#""track_name":"カタオモイ""#));
+        assert_eq!(queued(&store), 0);
+    }
+
+    #[test]
+    fn test_lastfm() {
+        let (url, requests) = mock_server(vec![200]);
+        let users = users(&format!("service = \"lastfm\"\nurl = \"{}/2.0/\"\napi_key = \"key\"\napi_secret = \"secret\"\nsession_key = \"sk\"", url));
+        let store = Store::memory().unwrap();
+        actix_web::rt::System::new().block_on(async {
+            Scrobbler::default().scrobble(&store, users.get("alice").unwrap(), &listen()).await.unwrap();
+        });
+
+        let request = requests.recv().unwrap();
+        assert!(request.starts_with("POST /2.0/ "));
+        assert!(request.contains("method=track.scrobble"));
+        assert!(request.contains("timestamp=1000"));
+        assert!(request.contains("api_sig="));
+        assert_eq!(lastfm_signature(&[("sk", "2"), ("api_key", "1")], "secret"), format!("{:x}", md5::compute("api_key1sk2secret")));
+    }
+
+    #[test]
+    fn test_retry_queue() {
+        let (url, requests) = mock_server(vec![503, 200, 400]);
+        let users = users(&format!("service = \"listenbrainz\"\nurl = \"{}\"\ntoken = \"abc\"", url));
+        let user = users.get("alice").unwrap();
+        let store = Store::memory().unwrap();
+        let scrobbler = Scrobbler::default();
+        actix_web::rt::System::new().block_on(async {
+            // server error, queued and retried successfully
+            scrobbler.scrobble(&store, user, &listen()).await.unwrap();
+            assert_eq!(queued(&store), 1);
+            scrobbler.flush(&store, &users).await.unwrap();
+            assert_eq!(queued(&store), 0);
+
+            // rejected, not queued
+            scrobbler.scrobble(&store, user, &listen()).await.unwrap();
+            assert_eq!(queued(&store), 0);
+        });
+        assert_eq!(requests.try_iter().count(), 3);
+    }
+}
@@ -0,0 +1,221 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use anni_repo::Album;
+use crate::repo::{Artist, songs};
+
+/// Whether a character should be tokenized as CJK text, which has no word separators.
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30FF}' // Hiragana & Katakana
+        | '\u{31F0}'..='\u{31FF}' // Katakana Phonetic Extensions
+        | '\u{3400}'..='\u{4DBF}' // CJK Unified Ideographs Extension A
+        | '\u{4E00}'..='\u{9FFF}' // CJK Unified Ideographs
+        | '\u{AC00}'..='\u{D7AF}' // Hangul Syllables
+        | '\u{F900}'..='\u{FAFF}' // CJK Compatibility Ideographs
+        | '\u{FF66}'..='\u{FF9F}' // Halfwidth Katakana
+    )
+}
+
+/// Fold fullwidth ASCII variants and letter case.
+fn normalize(c: char) -> impl Iterator<Item=char> {
+    let c = match c {
+        '\u{FF01}'..='\u{FF5E}' => std::char::from_u32(c as u32 - 0xFEE0).unwrap_or(c),
+        _ => c,
+    };
+    c.to_lowercase()
+}
+
+enum Run {
+    Word(String),
+    Cjk(Vec<char>),
+}
+
+fn runs(text: &str) -> Vec<Run> {
+    let mut result = Vec::new();
+    let mut current: Option<Run> = None;
+    for c in text.chars().flat_map(normalize) {
+        current = match (current.take(), is_cjk(c), c.is_alphanumeric()) {
+            (Some(Run::Cjk(mut chars)), true, _) => {
+                chars.push(c);
+                Some(Run::Cjk(chars))
+            }
+            (Some(Run::Word(mut word)), false, true) => {
+                word.push(c);
+                Some(Run::Word(word))
+            }
+            (last, cjk, alphanumeric) => {
+                result.extend(last);
+                if cjk {
+                    Some(Run::Cjk(vec![c]))
+                } else if alphanumeric {
+                    Some(Run::Word(c.to_string()))
+                } else {
+                    None
+                }
+            }
+        };
+    }
+    result.extend(current);
+    result
+}
+
+/// Split text into tokens for indexing.
+///
+/// Latin text is split into words, while CJK text is split into overlapping bigrams.
+/// The last character of a CJK run is emitted as a unigram, so that every character
+/// starts at least one token and can be found by prefix search.
+pub fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    for run in runs(text) {
+        match run {
+            Run::Word(word) => tokens.push(word),
+            Run::Cjk(chars) => {
+                for i in 0..chars.len() {
+                    tokens.push(chars[i..chars.len().min(i + 2)].iter().collect());
+                }
+            }
+        }
+    }
+    tokens
+}
+
+/// Split a search query into tokens.
+///
+/// Unlike [tokenize], trailing unigrams are only emitted for single character CJK runs,
+/// as they are already covered by the prefix of the previous bigram.
+pub fn tokenize_query(query: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    for run in runs(query) {
+        match run {
+            Run::Word(word) => tokens.push(word),
+            Run::Cjk(chars) if chars.len() == 1 => tokens.push(chars[0].to_string()),
+            Run::Cjk(chars) => {
+                for pair in chars.windows(2) {
+                    tokens.push(pair.iter().collect());
+                }
+            }
+        }
+    }
+    tokens
+}
+
+/// An inverted index from tokens to documents.
+///
+/// Documents are kept in insertion order, which is also the order of search results.
+pub struct Index<T> {
+    docs: Vec<T>,
+    tokens: BTreeMap<String, Vec<usize>>,
+}
+
+impl<T> Index<T> {
+    fn new() -> Self {
+        Self { docs: Vec::new(), tokens: BTreeMap::new() }
+    }
+
+    fn insert(&mut self, doc: T, texts: &[&str]) {
+        let id = self.docs.len();
+        self.docs.push(doc);
+        for text in texts {
+            for token in tokenize(text) {
+                let postings = self.tokens.entry(token).or_default();
+                if postings.last() != Some(&id) {
+                    postings.push(id);
+                }
+            }
+        }
+    }
+
+    /// Documents containing a token starting with `prefix`, sorted by id.
+    fn prefixed(&self, prefix: &str) -> Vec<usize> {
+        let mut result: Vec<usize> = self.tokens
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(token, _)| token.starts_with(prefix))
+            .flat_map(|(_, ids)| ids.iter().copied())
+            .collect();
+        result.sort_unstable();
+        result.dedup();
+        result
+    }
+
+    /// Search documents containing all tokens in query.
+    ///
+    /// Every token in query is matched as a prefix, and an empty query matches all documents.
+    pub fn search(&self, query: &str) -> Vec<&T> {
+        let mut result: Option<Vec<usize>> = None;
+        for token in tokenize_query(query) {
+            let ids = self.prefixed(&token);
+            result = Some(match result {
+                None => ids,
+                Some(result) => result.into_iter().filter(|id| ids.binary_search(id).is_ok()).collect(),
+            });
+        }
+        match result {
+            Some(ids) => ids.into_iter().map(|id| &self.docs[id]).collect(),
+            None => self.docs.iter().collect(),
+        }
+    }
+}
+
+pub struct SearchIndex {
+    pub artists: Index<String>,
+    pub albums: Index<String>,
+    /// song ids, see [Song::id](crate::repo::Song::id)
+    pub songs: Index<String>,
+}
+
+impl SearchIndex {
+    /// Build search index from albums.
+    ///
+    /// Albums should be sorted, as search results follow the same order.
+    /// Artists are sorted by name here.
+    pub fn new<'a>(albums: impl Iterator<Item=&'a Album>, artists: impl Iterator<Item=&'a Artist>) -> Self {
+        let mut index = Self {
+            artists: Index::new(),
+            albums: Index::new(),
+            songs: Index::new(),
+        };
+        let mut artists: Vec<_> = artists.collect();
+        artists.sort_by(|a, b| a.name.cmp(&b.name));
+        for artist in artists {
+            index.artists.insert(artist.id.clone(), &[&artist.name]);
+        }
+        for album in albums {
+            index.albums.insert(album.catalog().to_string(), &[album.title(), album.artist(), album.catalog()]);
+            for song in songs(album) {
+                index.songs.insert(song.id(), &[song.track.title(), song.track.artist(), album.title()]);
+            }
+        }
+        index
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::search::{tokenize, tokenize_query, Index};
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(tokenize("Hello, World!"), vec!["hello", "world"]);
+        assert_eq!(tokenize("ＬｏｖｅＬｉｖｅ！"), vec!["lovelive"]);
+        assert_eq!(tokenize("君の名は。"), vec!["君の", "の名", "名は", "は"]);
+        assert_eq!(tokenize("Aimer「カタオモイ」"), vec!["aimer", "カタ", "タオ", "オモ", "モイ", "イ"]);
+        assert_eq!(tokenize_query("名は"), vec!["名は"]);
+        assert_eq!(tokenize_query("君の名"), vec!["君の", "の名"]);
+        assert_eq!(tokenize_query("名"), vec!["名"]);
+    }
+
+    #[test]
+    fn test_search() {
+        let mut index = Index::new();
+        index.insert("LACA-0001", &["カタオモイ", "Aimer"]);
+        index.insert("LACA-0002", &["君の名は。", "RADWIMPS"]);
+        index.insert("LACA-0003", &["Hello World", "Aimer"]);
+
+        assert_eq!(index.search("aim"), vec![&"LACA-0001", &"LACA-0003"]);
+        assert_eq!(index.search("オモイ"), vec![&"LACA-0001"]);
+        assert_eq!(index.search("は"), vec![&"LACA-0002"]);
+        assert_eq!(index.search("aimer world"), vec![&"LACA-0003"]);
+        assert_eq!(index.search("laca"), Vec::<&&str>::new());
+        assert_eq!(index.search("").len(), 3);
+    }
+}
@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use rusqlite::params;
+use crate::store::{Store, now};
+use crate::models::iso8601;
+
+/// Items starred by a user.
+///
+/// Item kind is told by id format: song ids contain `/`, artists start with `ar-`
+/// and all other ids are album catalogs.
+pub struct Stars {
+    /// item id -> starred time, in the order of being starred
+    items: Vec<(String, i64)>,
+    index: HashMap<String, usize>,
+}
+
+impl Stars {
+    /// Starred time of item in ISO 8601.
+    pub fn get(&self, id: &str) -> Option<String> {
+        self.index.get(id).map(|i| iso8601(self.items[*i].1))
+    }
+
+    pub fn songs(&self) -> impl Iterator<Item=&str> {
+        self.ids().filter(|id| id.contains('/'))
+    }
+
+    pub fn artists(&self) -> impl Iterator<Item=&str> {
+        self.ids().filter(|id| id.starts_with("ar-"))
+    }
+
+    pub fn albums(&self) -> impl Iterator<Item=&str> {
+        self.ids().filter(|id| !id.contains('/') && !id.starts_with("ar-"))
+    }
+
+    fn ids(&self) -> impl Iterator<Item=&str> {
+        self.items.iter().map(|(id, _)| id.as_str())
+    }
+}
+
+impl Store {
+    pub fn star(&self, username: &str, ids: &[String]) -> rusqlite::Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let now = now();
+        for id in ids {
+            tx.execute("INSERT OR IGNORE INTO star (username, item_id, created) VALUES (?1, ?2, ?3)", params![username, id, now])?;
+        }
+        tx.commit()
+    }
+
+    pub fn unstar(&self, username: &str, ids: &[String]) -> rusqlite::Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        for id in ids {
+            tx.execute("DELETE FROM star WHERE username = ?1 AND item_id = ?2", params![username, id])?;
+        }
+        tx.commit()
+    }
+
+    pub fn stars(&self, username: &str) -> rusqlite::Result<Stars> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT item_id, created FROM star WHERE username = ?1 ORDER BY created, rowid")?;
+        let items = stmt.query_map(params![username], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<(String, i64)>>>()?;
+        let index = items.iter().enumerate().map(|(i, (id, _))| (id.clone(), i)).collect();
+        Ok(Stars { items, index })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::Store;
+
+    #[test]
+    fn test_star() {
+        let store = Store::memory().unwrap();
+        let ids = vec!["TEST-001".to_string(), "TEST-001/1".to_string(), "ar-0123".to_string()];
+        store.star("alice", &ids).unwrap();
+        // starring twice keeps the first time
+        store.star("alice", &ids[0..1]).unwrap();
+
+        let stars = store.stars("alice").unwrap();
+        assert!(stars.get("TEST-001").is_some());
+        assert_eq!(stars.albums().collect::<Vec<_>>(), vec!["TEST-001"]);
+        assert_eq!(stars.songs().collect::<Vec<_>>(), vec!["TEST-001/1"]);
+        assert_eq!(stars.artists().collect::<Vec<_>>(), vec!["ar-0123"]);
+        assert!(store.stars("bob").unwrap().get("TEST-001").is_none());
+
+        store.unstar("alice", &ids[1..]).unwrap();
+        assert_eq!(store.stars("alice").unwrap().songs().count(), 0);
+    }
+}
@@ -0,0 +1,248 @@
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use rusqlite::{params, Connection, NO_PARAMS};
+
+/// Schema migrations, `user_version` of database is the number of applied migrations.
+///
+/// Items are keyed by the ids exposed to clients, so that data survives metadata repository changes:
+/// - song: `{catalog}/{track_id}`, or `{catalog}/{disc_id}/{track_id}` in multi-disc albums
+/// - album: `{catalog}`
+/// - artist: `ar-{md5(name)}`
+const MIGRATIONS: &[&str] = &[
+    // 1: initial schema
+    r#"
+CREATE TABLE star (
+    username TEXT NOT NULL,
+    item_id TEXT NOT NULL,
+    created INTEGER NOT NULL,
+    PRIMARY KEY (username, item_id)
+);
+CREATE TABLE rating (
+    username TEXT NOT NULL,
+    item_id TEXT NOT NULL,
+    rating INTEGER NOT NULL,
+    PRIMARY KEY (username, item_id)
+);
+CREATE TABLE play_count (
+    username TEXT NOT NULL,
+    item_id TEXT NOT NULL,
+    count INTEGER NOT NULL,
+    last_played INTEGER NOT NULL,
+    PRIMARY KEY (username, item_id)
+);
+CREATE TABLE play_history (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    username TEXT NOT NULL,
+    song_id TEXT NOT NULL,
+    time INTEGER NOT NULL
+);
+CREATE INDEX play_history_username ON play_history (username, time);
+CREATE TABLE playlist (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    owner TEXT NOT NULL,
+    name TEXT NOT NULL,
+    comment TEXT NOT NULL DEFAULT '',
+    public INTEGER NOT NULL DEFAULT 0,
+    created INTEGER NOT NULL,
+    changed INTEGER NOT NULL
+);
+CREATE TABLE playlist_entry (
+    playlist_id INTEGER NOT NULL REFERENCES playlist (id) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    song_id TEXT NOT NULL,
+    PRIMARY KEY (playlist_id, position)
+);
+CREATE TABLE play_queue (
+    username TEXT PRIMARY KEY,
+    current TEXT,
+    position INTEGER NOT NULL DEFAULT 0,
+    changed INTEGER NOT NULL,
+    changed_by TEXT NOT NULL DEFAULT '',
+    entries TEXT NOT NULL DEFAULT ''
+);
+CREATE TABLE bookmark (
+    username TEXT NOT NULL,
+    song_id TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    comment TEXT NOT NULL DEFAULT '',
+    created INTEGER NOT NULL,
+    changed INTEGER NOT NULL,
+    PRIMARY KEY (username, song_id)
+);
+"#,
+    // 2: scrobbles waiting to be forwarded
+    r#"
+CREATE TABLE scrobble_queue (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    username TEXT NOT NULL,
+    service TEXT NOT NULL,
+    artist TEXT NOT NULL,
+    track TEXT NOT NULL,
+    album TEXT NOT NULL,
+    time INTEGER NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_retry INTEGER NOT NULL
+);
+CREATE INDEX scrobble_queue_next_retry ON scrobble_queue (next_retry);
+"#,
+    // 3: cache of probed track information, keyed by path in annil
+    r#"
+CREATE TABLE track_info (
+    path TEXT PRIMARY KEY,
+    size INTEGER NOT NULL,
+    content_type TEXT NOT NULL,
+    duration INTEGER,
+    bit_rate INTEGER
+);
+"#,
+    // 4: api keys of OpenSubsonic, only hashes are stored
+    r#"
+CREATE TABLE api_key (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    username TEXT NOT NULL,
+    name TEXT NOT NULL DEFAULT '',
+    hash TEXT NOT NULL UNIQUE,
+    created INTEGER NOT NULL,
+    last_used INTEGER
+);
+"#,
+];
+
+/// Local writable storage for user data.
+pub struct Store {
+    conn: Mutex<Connection>,
+}
+
+impl Store {
+    pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        Self::new(Connection::open(path)?)
+    }
+
+    #[cfg(test)]
+    pub fn memory() -> anyhow::Result<Self> {
+        Self::new(Connection::open_in_memory()?)
+    }
+
+    fn new(mut conn: Connection) -> anyhow::Result<Self> {
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        migrate(&mut conn)?;
+        Ok(Self { conn: Mutex::new(conn) })
+    }
+
+    pub fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap()
+    }
+
+    /// Rewrite ids of songs and albums to those returned by `map`, returns the number of ids rewritten.
+    ///
+    /// `map` returns `None` for ids which are up to date, see [`crate::repo::RepoManager::current_id`].
+    /// Items already stored under the new id are kept, while play counts are added up.
+    pub fn migrate_ids<F: Fn(&str) -> Option<String>>(&self, map: F) -> rusqlite::Result<usize> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let ids = {
+            let mut stmt = tx.prepare(
+                "SELECT item_id FROM star UNION SELECT item_id FROM rating UNION SELECT item_id FROM play_count
+                UNION SELECT song_id FROM play_history UNION SELECT song_id FROM playlist_entry",
+            )?;
+            let ids = stmt.query_map(NO_PARAMS, |row| row.get::<_, String>(0))?.collect::<rusqlite::Result<Vec<_>>>()?;
+            ids
+        };
+        let mut migrated = 0;
+        for (old, new) in ids.iter().filter_map(|id| map(id).map(|new| (id, new))) {
+            tx.execute("INSERT OR IGNORE INTO star (username, item_id, created) SELECT username, ?2, created FROM star WHERE item_id = ?1", params![old, new])?;
+            tx.execute("DELETE FROM star WHERE item_id = ?1", params![old])?;
+            tx.execute("INSERT OR IGNORE INTO rating (username, item_id, rating) SELECT username, ?2, rating FROM rating WHERE item_id = ?1", params![old, new])?;
+            tx.execute("DELETE FROM rating WHERE item_id = ?1", params![old])?;
+            tx.execute(
+                "INSERT INTO play_count (username, item_id, count, last_played) SELECT username, ?2, count, last_played FROM play_count WHERE item_id = ?1
+                ON CONFLICT (username, item_id) DO UPDATE SET count = count + excluded.count, last_played = max(last_played, excluded.last_played)",
+                params![old, new],
+            )?;
+            tx.execute("DELETE FROM play_count WHERE item_id = ?1", params![old])?;
+            tx.execute("UPDATE play_history SET song_id = ?2 WHERE song_id = ?1", params![old, new])?;
+            tx.execute("UPDATE playlist_entry SET song_id = ?2 WHERE song_id = ?1", params![old, new])?;
+            migrated += 1;
+        }
+        tx.commit()?;
+        Ok(migrated)
+    }
+
+    /// Catalogs of albums rated by user, highest rated first.
+    pub fn rated_albums(&self, username: &str) -> rusqlite::Result<Vec<String>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT item_id FROM rating WHERE username = ?1 AND item_id NOT LIKE '%/%' AND item_id NOT LIKE 'ar-%'
+            ORDER BY rating DESC, item_id",
+        )?;
+        let result = stmt.query_map(params![username], |row| row.get(0))?.collect();
+        result
+    }
+}
+
+/// Current unix timestamp in seconds.
+pub fn now() -> i64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() as i64
+}
+
+fn migrate(conn: &mut Connection) -> anyhow::Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get::<_, i64>(0))? as usize;
+    if version > MIGRATIONS.len() {
+        anyhow::bail!("Database version {} is newer than supported version {}", version, MIGRATIONS.len());
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        log::info!("Migrating database to version {}", i + 1);
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.execute_batch(&format!("PRAGMA user_version = {};", i + 1))?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::{Store, MIGRATIONS, migrate};
+    use rusqlite::{params, NO_PARAMS};
+
+    #[test]
+    fn test_migrate() {
+        let store = Store::memory().unwrap();
+        let mut conn = store.conn();
+        // migrating again should be a no-op
+        migrate(&mut conn).unwrap();
+        let version: i64 = conn.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0)).unwrap();
+        assert_eq!(version as usize, MIGRATIONS.len());
+    }
+
+    #[test]
+    fn test_migrate_ids() {
+        let store = Store::memory().unwrap();
+        store.star("alice", &["DISC-2/1".to_string(), "TEST-001/2/1".to_string(), "TEST-002/1".to_string()]).unwrap();
+        store.record_play("alice", "DISC-2/1", 100).unwrap();
+        store.record_play("alice", "TEST-001/2/1", 200).unwrap();
+        let map = |id: &str| match id {
+            "DISC-2/1" => Some("TEST-001/2/1".to_string()),
+            "DISC-2" => Some("TEST-001".to_string()),
+            _ => None,
+        };
+        assert_eq!(store.migrate_ids(map).unwrap(), 2);
+        assert_eq!(store.migrate_ids(map).unwrap(), 0);
+
+        let stars = store.stars("alice").unwrap();
+        assert_eq!(stars.songs().collect::<Vec<_>>(), vec!["TEST-001/2/1", "TEST-002/1"]);
+        let plays = store.plays("alice").unwrap();
+        assert_eq!(plays.count("TEST-001/2/1"), Some(2));
+        assert_eq!(plays.count("TEST-001"), Some(2));
+        assert_eq!(plays.count("DISC-2"), None);
+    }
+
+    #[test]
+    fn test_rated_albums() {
+        let store = Store::memory().unwrap();
+        for (username, id, rating) in [("alice", "TEST-001", 3), ("alice", "TEST-002", 5), ("alice", "TEST-002/1", 5), ("alice", "ar-1", 5), ("alice", "TEST-003", 3), ("bob", "TEST-004", 5)] {
+            store.conn().execute("INSERT INTO rating (username, item_id, rating) VALUES (?1, ?2, ?3)", params![username, id, rating]).unwrap();
+        }
+        assert_eq!(store.rated_albums("alice").unwrap(), vec!["TEST-002", "TEST-001", "TEST-003"]);
+    }
+}
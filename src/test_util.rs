@@ -0,0 +1,62 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc;
+use crate::config::Config;
+
+/// Config with user `alice` whose password is `sesame`, followed by `extra` toml.
+///
+/// `extra` continues the last table, so `[[users.scrobble]]` belongs to `alice`.
+pub fn config(extra: &str) -> Config {
+    toml::from_str(&format!(r#"
+[server]
+[repo]
+root = "repo"
+[[users]]
+username = "alice"
+password = "sesame"
+{}
+"#, extra)).unwrap()
+}
+
+/// Start an HTTP server answering each request with status and body from `respond`.
+///
+/// Returns url of the server, and raw requests received, which are sent before the response.
+pub fn mock_server<F>(respond: F) -> (String, mpsc::Receiver<String>)
+    where F: Fn(&str) -> (u16, String) + Send + 'static
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut reader = BufReader::new(stream.unwrap());
+            let mut request = String::new();
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+                request.push_str(&line);
+                if line == "\r\n" || line.is_empty() {
+                    break;
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            request.push_str(&String::from_utf8_lossy(&body));
+
+            let (status, body) = respond(&request);
+            // receiver may be dropped by tests not checking requests
+            let _ = tx.send(request);
+            write!(reader.get_mut(), "HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body).unwrap();
+        }
+    });
+    (url, rx)
+}
+
+/// Path of raw request, without query string.
+pub fn path(request: &str) -> &str {
+    request.split(' ').nth(1).unwrap_or_default().split('?').next().unwrap_or_default()
+}
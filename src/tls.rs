@@ -0,0 +1,163 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::{Arc, RwLock};
+use actix_web::{web, HttpRequest, HttpResponse, http};
+use anyhow::Context;
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::{self, CertifiedKey};
+use crate::config::TlsConfig;
+
+/// Certificate of HTTPS listener, which can be reloaded without restarting server.
+pub struct CertResolver {
+    cert_path: String,
+    key_path: String,
+    key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    pub fn new(config: &TlsConfig) -> anyhow::Result<Self> {
+        let key = load(&config.cert, &config.key)?;
+        Ok(Self {
+            cert_path: config.cert.clone(),
+            key_path: config.key.clone(),
+            key: RwLock::new(Arc::new(key)),
+        })
+    }
+
+    /// Load certificate and key again from files, the current ones are kept on failure.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let key = load(&self.cert_path, &self.key_path)?;
+        *self.key.write().unwrap() = Arc::new(key);
+        Ok(())
+    }
+
+    pub fn server_config(self: Arc<Self>) -> ServerConfig {
+        ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(self)
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.key.read().unwrap().clone())
+    }
+}
+
+fn load(cert_path: &str, key_path: &str) -> anyhow::Result<CertifiedKey> {
+    let mut reader = BufReader::new(File::open(cert_path).with_context(|| format!("Failed to open certificate {}", cert_path))?);
+    let certs: Vec<_> = rustls_pemfile::certs(&mut reader)?.into_iter().map(Certificate).collect();
+    if certs.is_empty() {
+        anyhow::bail!("No certificate found in {}", cert_path);
+    }
+
+    let mut reader = BufReader::new(File::open(key_path).with_context(|| format!("Failed to open private key {}", key_path))?);
+    let key = rustls_pemfile::read_all(&mut reader)?.into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow::anyhow!("No private key found in {}", key_path))?;
+    let key = sign::any_supported_type(&key).map_err(|_| anyhow::anyhow!("Unsupported private key in {}", key_path))?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// Reload certificate on SIGHUP, for renewing certificates without dropping connections.
+#[cfg(unix)]
+pub async fn reload_on_hangup(resolver: Arc<CertResolver>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            log::error!("Failed to listen for SIGHUP, certificate will not be reloaded: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        match resolver.reload() {
+            Ok(()) => log::info!("Certificate reloaded"),
+            Err(e) => log::error!("Failed to reload certificate, keeping the current one: {}", e),
+        }
+    }
+}
+
+/// `host` without port, keeping brackets of IPv6 addresses.
+fn strip_port(host: &str) -> &str {
+    match host.find(']') {
+        Some(end) => &host[..=end],
+        None => host.split(':').next().unwrap_or(host),
+    }
+}
+
+/// Address of the same resource on HTTPS listener at `port`.
+fn https_url(host: &str, port: u16, uri: &str) -> String {
+    let host = strip_port(host);
+    match port {
+        443 => format!("https://{}{}", host, uri),
+        port => format!("https://{}:{}{}", host, port, uri),
+    }
+}
+
+/// Where plain HTTP requests are redirected to.
+pub struct Redirect {
+    port: u16,
+    server_names: Vec<String>,
+}
+
+impl Redirect {
+    pub fn new(config: &TlsConfig) -> anyhow::Result<Self> {
+        if config.server_names.is_empty() {
+            anyhow::bail!("tls.server_names is required to redirect plain HTTP requests");
+        }
+        Ok(Self { port: config.port()?, server_names: config.server_names.clone() })
+    }
+
+    /// Configured server name matching `host`, or the first one.
+    ///
+    /// `Host` is sent by clients, so it is never used as is, or anyone could make this an open redirect.
+    fn server_name(&self, host: Option<&str>) -> &str {
+        let host = host.map(strip_port).unwrap_or_default();
+        self.server_names.iter()
+            .find(|name| name.eq_ignore_ascii_case(host))
+            .unwrap_or(&self.server_names[0])
+    }
+}
+
+/// Redirect plain HTTP requests to HTTPS listener, see [`Redirect`] in app data.
+///
+/// `308` is used so that clients keep method and body.
+pub async fn redirect(req: HttpRequest, redirect: web::Data<Redirect>) -> HttpResponse {
+    let host = req.headers().get(http::header::HOST).and_then(|host| host.to_str().ok());
+    let url = https_url(redirect.server_name(host), redirect.port, &req.uri().to_string());
+    HttpResponse::PermanentRedirect()
+        .insert_header((http::header::LOCATION, url))
+        .finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::TlsConfig;
+    use crate::tls::{https_url, Redirect};
+
+    #[test]
+    fn test_https_url() {
+        assert_eq!(https_url("music.example.com:1710", 1711, "/rest/ping.view?u=a"), "https://music.example.com:1711/rest/ping.view?u=a");
+        assert_eq!(https_url("music.example.com", 443, "/rest/ping.view"), "https://music.example.com/rest/ping.view");
+        assert_eq!(https_url("[::1]:1710", 1711, "/"), "https://[::1]:1711/");
+    }
+
+    #[test]
+    fn test_redirect() {
+        let config: TlsConfig = toml::from_str("cert = \"cert.pem\"\nkey = \"key.pem\"").unwrap();
+        assert!(Redirect::new(&config).is_err());
+
+        let config: TlsConfig = toml::from_str("cert = \"cert.pem\"\nkey = \"key.pem\"\nserver_names = [\"music.example.com\", \"music.example.org\"]").unwrap();
+        let redirect = Redirect::new(&config).unwrap();
+        assert_eq!(redirect.server_name(Some("Music.Example.org:1710")), "music.example.org");
+        assert_eq!(redirect.server_name(Some("evil.example.net")), "music.example.com");
+        assert_eq!(redirect.server_name(None), "music.example.com");
+    }
+}
@@ -0,0 +1,128 @@
+use std::io;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::task::{Context, Poll};
+use actix_web::HttpResponse;
+use actix_web::web::Bytes;
+use futures_util::{Stream, StreamExt};
+use tokio::io::AsyncWriteExt;
+use tokio::process::{Child, ChildStdout, Command};
+use reqwest::StatusCode;
+use tokio_util::io::ReaderStream;
+use crate::backend::Backends;
+use crate::config::{TranscodeConfig, TranscodeProfile};
+use crate::error::{self, Error};
+
+/// Transcode `path` on annil with ffmpeg.
+///
+/// Audio is fetched from annil and piped into ffmpeg, while ffmpeg output is streamed to client.
+/// Transcoded stream does not support seeking by range, use `time_offset` instead.
+/// `length` is sent as `Content-Length` if known, see [`estimate_length`].
+/// Songs missing in annil are [`Error::NotFound`].
+pub async fn transcode(config: &TranscodeConfig, backends: &Backends, path: &str, profile: &TranscodeProfile, bitrate: u32, time_offset: Option<u32>, length: Option<u64>) -> error::Result<HttpResponse> {
+    let response = match backends.request(path, |request| request).await {
+        Ok(response) if response.status() == StatusCode::NOT_FOUND => return Err(Error::not_found("Song")),
+        result => result.and_then(|r| Ok(r.error_for_status().map_err(reqwest::Error::without_url)?)),
+    };
+    let response = match response {
+        Ok(response) => response,
+        Err(e) => {
+            log::error!("Failed to fetch {} from annil: {}", path, e);
+            return Ok(HttpResponse::BadGateway().finish());
+        }
+    };
+
+    let mut command = Command::new(&config.ffmpeg);
+    command.args(["-v", "error", "-nostdin"]);
+    if let Some(offset) = time_offset {
+        command.args(["-ss", &offset.to_string()]);
+    }
+    command
+        .args(["-i", "pipe:0", "-map", "0:a:0", "-vn"])
+        .args(["-c:a", &profile.codec, "-b:a", &format!("{}k", bitrate)])
+        .args(&profile.args)
+        .args(["-f", &profile.container, "pipe:1"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true);
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            log::error!("Failed to start ffmpeg: {}", e);
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+    };
+
+    let mut stdin = child.stdin.take().unwrap();
+    let stdout = child.stdout.take().unwrap();
+    let path = path.to_string();
+    actix_web::rt::spawn(async move {
+        let mut input = response.bytes_stream();
+        while let Some(chunk) = input.next().await {
+            let result = match chunk {
+                Ok(chunk) => stdin.write_all(&chunk).await,
+                Err(e) => {
+                    log::error!("Failed to read {} from annil: {}", path, e);
+                    break;
+                }
+            };
+            // ffmpeg is killed when client disconnects, see `Output`
+            if result.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut builder = HttpResponse::Ok();
+    builder.content_type(profile.content_type.as_str());
+    if let Some(length) = length {
+        builder.no_chunking(length);
+    }
+    Ok(builder.streaming(Output { stdout: ReaderStream::new(stdout), _child: child }))
+}
+
+/// Size in bytes of `duration` seconds transcoded at `bitrate` kbps, starting from `time_offset`.
+///
+/// Container overhead is ignored, so it is only an estimate for clients needing the size in advance.
+pub fn estimate_length(duration: u32, bitrate: u32, time_offset: Option<u32>) -> u64 {
+    duration.saturating_sub(time_offset.unwrap_or(0)) as u64 * bitrate as u64 * 1000 / 8
+}
+
+/// Output of ffmpeg, owning the process so that it is killed once response is dropped.
+struct Output {
+    stdout: ReaderStream<ChildStdout>,
+    _child: Child,
+}
+
+impl Stream for Output {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.stdout).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::Backends;
+    use crate::test_util;
+    use crate::transcode::{estimate_length, transcode};
+
+    #[test]
+    fn test_estimate_length() {
+        assert_eq!(estimate_length(240, 128, None), 3_840_000);
+        assert_eq!(estimate_length(240, 128, Some(40)), 3_200_000);
+        assert_eq!(estimate_length(240, 128, Some(300)), 0);
+    }
+
+    #[test]
+    fn test_not_found() {
+        actix_web::rt::System::new().block_on(async {
+            let (url, _) = test_util::mock_server(|_| (404, String::new()));
+            let backends = Backends::new(vec![toml::from_str(&format!("server = \"{}\"\ntoken = \"token\"", url)).unwrap()]);
+            let config = test_util::config("").transcode;
+            let result = transcode(&config, &backends, "TEST-001/1", config.profile("mp3").unwrap(), 128, None, None).await;
+            assert_eq!(result.err().map(|e| e.code()), Some(70));
+        });
+    }
+}
@@ -0,0 +1,39 @@
+use std::collections::HashMap;
+use crate::config::{Config, UserConfig, Role};
+
+/// Users allowed to access annisonic, keyed by username.
+pub struct Users {
+    users: HashMap<String, UserConfig>,
+}
+
+impl Users {
+    pub fn new(config: &Config) -> Self {
+        let mut users = HashMap::new();
+        for user in config.users.iter() {
+            users.insert(user.username.clone(), user.clone());
+        }
+        // legacy single user in [server] section
+        if let (Some(username), Some(password)) = (&config.server.username, &config.server.password) {
+            users.entry(username.clone()).or_insert_with(|| UserConfig {
+                username: username.clone(),
+                password: password.clone(),
+                roles: vec![Role::Stream, Role::Download, Role::Playlist, Role::Admin],
+                folders: vec!["@".to_string()],
+                scrobble: Vec::new(),
+            });
+        }
+        Self { users }
+    }
+
+    pub fn get(&self, username: &str) -> Option<&UserConfig> {
+        self.users.get(username)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&UserConfig> {
+        self.users.values()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+}